@@ -1,9 +1,139 @@
+use crate::config::Config;
+use crate::parse_report::RejectedLine;
 use crate::processing_error;
-use std::{collections::HashMap, fs::File, io::Write};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+};
 
 use processing_error::ProcessingError;
 use serde_json::Value;
 
+/// Paths of the output files: a CSV file per entity type (and language), the KV store and, optionally,
+/// the lines that could not be parsed, the GeoJSON features (newline-delimited) and the label table
+#[derive(Debug, Clone)]
+pub struct OutputPaths {
+    pub csv: Vec<(String, PathBuf)>,
+    pub kv: PathBuf,
+    pub rejects: Option<PathBuf>,
+    pub geojson: Option<PathBuf>,
+    pub labels: Option<PathBuf>,
+}
+
+impl OutputPaths {
+    /// Output paths in the output directory, optionally for a numbered part of the input
+    pub fn new(config: &Config, part: Option<usize>) -> Self {
+        let suffix = part.map_or(String::new(), |part| format!(".part-{:04}", part));
+        let suffix = suffix.as_str();
+        let csv = config
+            .entity_types
+            .iter()
+            .flat_map(|entity_type| {
+                config.languages.iter().map(move |lang| {
+                    let name = Self::csv_name(entity_type, lang, &config.languages);
+                    let path = format!("{}/{}.csv{}", config.output_dir, name, suffix);
+                    (name, PathBuf::from(path))
+                })
+            })
+            .collect();
+        let kv = PathBuf::from(format!(
+            "{}/entity_kv_store.{}{}",
+            config.output_dir,
+            match config.output_format.as_str() {
+                "JSONLines" => "jsonl",
+                _ => "msgpack",
+            },
+            suffix
+        ));
+        let rejects = config
+            .rejects
+            .then(|| PathBuf::from(format!("{}/rejects.jsonl{}", config.output_dir, suffix)));
+        let geojson = config
+            .geojson
+            .is_some()
+            .then(|| PathBuf::from(format!("{}/entities.geojsonl{}", config.output_dir, suffix)));
+        let labels = (config.resolver == "offline")
+            .then(|| PathBuf::from(format!("{}/labels.csv{}", config.output_dir, suffix)));
+        Self {
+            csv,
+            kv,
+            rejects,
+            geojson,
+            labels,
+        }
+    }
+
+    /// Name of the CSV file with the names of an entity type in a language, without the
+    /// extension: the entity type, followed by the language when there are multiple languages
+    pub fn csv_name(entity_type: &str, lang: &str, languages: &[String]) -> String {
+        if languages.len() == 1 {
+            entity_type.to_string()
+        } else {
+            format!("{}.{}", entity_type, lang)
+        }
+    }
+
+    /// All output files
+    pub fn all(&self) -> Vec<&PathBuf> {
+        self.csv
+            .iter()
+            .map(|(_, path)| path)
+            .chain(std::iter::once(&self.kv))
+            .chain(self.rejects.iter())
+            .chain(self.geojson.iter())
+            .chain(self.labels.iter())
+            .collect()
+    }
+
+    /// Names of all output files
+    pub fn file_names(&self) -> Vec<String> {
+        self.all()
+            .iter()
+            .map(|path| {
+                path.file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    /// Current lengths of all output files
+    pub fn lengths(&self) -> Result<Vec<u64>, ProcessingError> {
+        let mut lengths = Vec::new();
+        for path in self.all() {
+            lengths.push(fs::metadata(path)?.len());
+        }
+        Ok(lengths)
+    }
+}
+
+/// Concatenate the parts, in order, into the target files. The parts are kept, so a crash
+/// during merging can be resumed: remove them with `remove_parts` once the checkpoint is gone.
+pub fn merge_parts(target: &OutputPaths, parts: &[OutputPaths]) -> Result<(), ProcessingError> {
+    for (index, target_path) in target.all().into_iter().enumerate() {
+        let mut output = File::create(target_path)?;
+        for part in parts {
+            io::copy(&mut File::open(part.all()[index])?, &mut output)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the merged parts
+pub fn remove_parts(parts: &[OutputPaths]) -> Result<(), ProcessingError> {
+    for part in parts {
+        for part_path in part.all() {
+            if part_path.exists() {
+                fs::remove_file(part_path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
 // Batched writer struct to handle buffered writes
 pub struct BatchedWriter {
     csv_writers: HashMap<String, Vec<(String, String)>>,
@@ -11,6 +141,11 @@ pub struct BatchedWriter {
     batch_size: usize,
     total_csv_writers: HashMap<String, csv::Writer<File>>,
     kv_file: File,
+    rejects_file: Option<File>,
+    features: Vec<Value>,
+    geojson_file: Option<File>,
+    label_rows: Vec<[String; 3]>,
+    labels_writer: Option<csv::Writer<File>>,
     output_format: String,
 }
 
@@ -26,11 +161,102 @@ impl BatchedWriter {
             total_csv_writers: csv_writers,
             kv_entries: Vec::with_capacity(batch_size),
             kv_file,
+            rejects_file: None,
+            features: Vec::new(),
+            geojson_file: None,
+            label_rows: Vec::new(),
+            labels_writer: None,
             output_format,
             batch_size,
         }
     }
 
+    /// Create the output files and a writer for them
+    pub fn create(
+        paths: &OutputPaths,
+        output_format: String,
+        batch_size: usize,
+    ) -> Result<Self, ProcessingError> {
+        let mut csv_writers = HashMap::new();
+        for (entity_type, path) in &paths.csv {
+            csv_writers.insert(entity_type.clone(), csv::Writer::from_path(path)?);
+        }
+        let kv_file = File::create(&paths.kv)?;
+        let mut writer = Self::new(csv_writers, kv_file, output_format, batch_size);
+        if let Some(rejects) = &paths.rejects {
+            writer.rejects_file = Some(File::create(rejects)?);
+        }
+        if let Some(geojson) = &paths.geojson {
+            writer.geojson_file = Some(File::create(geojson)?);
+        }
+        if let Some(labels) = &paths.labels {
+            writer.labels_writer = Some(csv::Writer::from_path(labels)?);
+        }
+        Ok(writer)
+    }
+
+    /// Truncate the output files to the lengths of a checkpoint, and append to them
+    pub fn resume(
+        paths: &OutputPaths,
+        lengths: &[u64],
+        output_format: String,
+        batch_size: usize,
+    ) -> Result<Self, ProcessingError> {
+        // A file may only be missing if nothing was written to it yet. A missing or shorter
+        // file means the output no longer matches the checkpoint.
+        let open = |path: &PathBuf, length: u64| -> Result<File, ProcessingError> {
+            let file = match OpenOptions::new().append(true).open(path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound && length == 0 => File::create(path)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(ProcessingError::CheckpointError(format!(
+                        "{} is missing, but had {} bytes at the checkpoint",
+                        path.display(),
+                        length
+                    )))
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let current = file.metadata()?.len();
+            if current < length {
+                return Err(ProcessingError::CheckpointError(format!(
+                    "{} has {} bytes, but had {} bytes at the checkpoint",
+                    path.display(),
+                    current,
+                    length
+                )));
+            }
+            file.set_len(length)?;
+            Ok(file)
+        };
+        // The lengths are in the order of `OutputPaths::all`
+        let mut lengths = lengths.iter().copied();
+        let mut next_length = || {
+            lengths.next().ok_or_else(|| {
+                ProcessingError::CheckpointError("missing output file lengths".to_string())
+            })
+        };
+        let mut csv_writers = HashMap::new();
+        for (entity_type, path) in &paths.csv {
+            csv_writers.insert(
+                entity_type.clone(),
+                csv::Writer::from_writer(open(path, next_length()?)?),
+            );
+        }
+        let kv_file = open(&paths.kv, next_length()?)?;
+        let mut writer = Self::new(csv_writers, kv_file, output_format, batch_size);
+        if let Some(rejects) = &paths.rejects {
+            writer.rejects_file = Some(open(rejects, next_length()?)?);
+        }
+        if let Some(geojson) = &paths.geojson {
+            writer.geojson_file = Some(open(geojson, next_length()?)?);
+        }
+        if let Some(labels) = &paths.labels {
+            writer.labels_writer = Some(csv::Writer::from_writer(open(labels, next_length()?)?));
+        }
+        Ok(writer)
+    }
+
     pub fn add_csv_entry(
         &mut self,
         entity_type: String,
@@ -38,7 +264,7 @@ impl BatchedWriter {
     ) -> Result<(), ProcessingError> {
         self.csv_writers
             .entry(entity_type)
-            .or_insert_with(Vec::new)
+            .or_default()
             .push(record);
 
         // Flush if batch is full
@@ -60,12 +286,43 @@ impl BatchedWriter {
         Ok(())
     }
 
+    /// Write a line that could not be parsed to the rejects file, if there is one.
+    /// Rejects are rare, so they are written immediately.
+    pub fn add_reject(&mut self, reject: &RejectedLine) -> Result<(), ProcessingError> {
+        if let Some(rejects_file) = &mut self.rejects_file {
+            writeln!(rejects_file, "{}", serde_json::to_string(reject)?)?;
+        }
+        Ok(())
+    }
+
+    /// Add a GeoJSON feature, if GeoJSON is written
+    pub fn add_feature(&mut self, feature: Value) -> Result<(), ProcessingError> {
+        if self.geojson_file.is_some() {
+            self.features.push(feature);
+            if self.features.len() >= self.batch_size {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the rows of an entity to the label table, if it is written
+    pub fn add_label_rows(&mut self, rows: Vec<[String; 3]>) -> Result<(), ProcessingError> {
+        if self.labels_writer.is_some() {
+            self.label_rows.extend(rows);
+            if self.label_rows.len() >= self.batch_size {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn flush(&mut self) -> Result<(), ProcessingError> {
         // Flush CSV entries
         for (entity_type, entries) in &self.csv_writers {
             if let Some(writer) = self.total_csv_writers.get_mut(entity_type) {
                 for (label, entity_id) in entries {
-                    writer.write_record(&[label, entity_id])?;
+                    writer.write_record([label, entity_id])?;
                 }
             }
         }
@@ -87,6 +344,22 @@ impl BatchedWriter {
             self.kv_entries.clear();
         }
 
+        // Flush GeoJSON features, one per line
+        if let Some(geojson_file) = &mut self.geojson_file {
+            for feature in &self.features {
+                writeln!(geojson_file, "{}", serde_json::to_string(feature)?)?;
+            }
+            self.features.clear();
+        }
+
+        // Flush the rows of the label table
+        if let Some(labels_writer) = &mut self.labels_writer {
+            for row in &self.label_rows {
+                labels_writer.write_record(row)?;
+            }
+            self.label_rows.clear();
+        }
+
         Ok(())
     }
 
@@ -98,7 +371,87 @@ impl BatchedWriter {
         for writer in self.total_csv_writers.values_mut() {
             writer.flush()?;
         }
+        if let Some(labels_writer) = &mut self.labels_writer {
+            labels_writer.flush()?;
+        }
+
+        Ok(())
+    }
 
+    /// Write all entries to the files on disk, so their lengths can be recorded in a checkpoint
+    pub fn sync(&mut self) -> Result<(), ProcessingError> {
+        self.finalize()?;
+        for writer in self.total_csv_writers.values_mut() {
+            writer.get_ref().sync_data()?;
+        }
+        self.kv_file.sync_data()?;
+        if let Some(rejects_file) = &self.rejects_file {
+            rejects_file.sync_data()?;
+        }
+        if let Some(geojson_file) = &self.geojson_file {
+            geojson_file.sync_data()?;
+        }
+        if let Some(labels_writer) = &self.labels_writer {
+            labels_writer.get_ref().sync_data()?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn resumes_from_the_lengths_of_a_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("batched_writer_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut config = Config::for_test(&dir);
+        config.output_format = "JSONLines".to_string();
+        config.entity_types = vec!["person".to_string()];
+        config.geojson = Some("NDJSON".to_string());
+        let paths = OutputPaths::new(&config, None);
+        let person = |id: &str| (id.to_string(), format!("Person {}", id));
+
+        let mut writer = BatchedWriter::create(&paths, config.output_format.clone(), 10).unwrap();
+        writer
+            .add_csv_entry("person".to_string(), person("Q1"))
+            .unwrap();
+        writer.add_kv_entry(json!({"Q1": {}})).unwrap();
+        writer.sync().unwrap();
+        let lengths = paths.lengths().unwrap();
+        // Written after the checkpoint, so lost when resuming
+        writer
+            .add_csv_entry("person".to_string(), person("Q2"))
+            .unwrap();
+        writer.add_kv_entry(json!({"Q2": {}})).unwrap();
+        writer.add_feature(json!({"id": "Q2"})).unwrap();
+        writer.finalize().unwrap();
+        drop(writer);
+
+        let mut writer =
+            BatchedWriter::resume(&paths, &lengths, config.output_format.clone(), 10).unwrap();
+        assert_eq!(paths.lengths().unwrap(), lengths);
+        writer
+            .add_csv_entry("person".to_string(), person("Q3"))
+            .unwrap();
+        writer.add_kv_entry(json!({"Q3": {}})).unwrap();
+        writer.finalize().unwrap();
+        drop(writer);
+        let csv = fs::read_to_string(&paths.csv[0].1).unwrap();
+        let kv = fs::read_to_string(&paths.kv).unwrap();
+        let features = fs::read_to_string(paths.geojson.as_ref().unwrap()).unwrap();
+
+        // A file that had output at the checkpoint must still be there
+        fs::remove_file(&paths.kv).unwrap();
+        let missing = BatchedWriter::resume(&paths, &lengths, config.output_format.clone(), 10);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(csv, "Q1,Person Q1\nQ3,Person Q3\n");
+        assert_eq!(kv, "{\"Q1\":{}}\n{\"Q3\":{}}\n");
+        assert_eq!(features, "");
+        assert!(matches!(missing, Err(ProcessingError::CheckpointError(_))));
+    }
+}
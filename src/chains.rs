@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+use crate::class_hierarchy::qid_number;
+use crate::wikibase::{best_rank_statements, parse_statements, DataValue};
+
+/// Maximum length of a chain, which also ends chains that contain a cycle
+const MAX_CHAIN_LENGTH: usize = 20;
+
+/// Properties that are followed from entity to entity, such as P131 (located in the
+/// administrative territorial entity): of each entity, the first value of the best rank
+#[derive(Default, Serialize, Deserialize)]
+pub struct PropertyChains {
+    /// Per property, the value of each entity, by numeric QID
+    parents: HashMap<String, HashMap<u64, u64>>,
+}
+
+/// The first item value of the best rank of each of the properties, as (property index,
+/// entity, value), of the claims of an entity
+pub fn chain_edges(
+    entity_id: u64,
+    claims: &HashMap<&str, &RawValue>,
+    properties: &[String],
+) -> Vec<(usize, u64, u64)> {
+    properties
+        .iter()
+        .enumerate()
+        .filter_map(|(index, property)| {
+            let statements = parse_statements(claims.get(property.as_str())?).0;
+            let parent = best_rank_statements(&statements)
+                .into_iter()
+                .find_map(|statement| match statement.mainsnak.datavalue()? {
+                    DataValue::EntityId(entity) => qid_number(&entity.id),
+                    _ => None,
+                })?;
+            Some((index, entity_id, parent))
+        })
+        .collect()
+}
+
+impl PropertyChains {
+    /// The chains of the edges (property index, entity, value) of all entities
+    pub fn from_edges(
+        properties: &[String],
+        edges: impl IntoIterator<Item = (usize, u64, u64)>,
+    ) -> Self {
+        let mut parents: HashMap<String, HashMap<u64, u64>> = properties
+            .iter()
+            .map(|property| (property.clone(), HashMap::new()))
+            .collect();
+        for (index, entity, parent) in edges {
+            parents
+                .get_mut(&properties[index])
+                .unwrap()
+                .insert(entity, parent);
+        }
+        Self { parents }
+    }
+
+    /// The chain starting at a value, e.g. the municipality an entity is located in, followed by
+    /// the province the municipality is located in, and the country of the province.
+    /// Values that are not items are returned as they are.
+    pub fn chain(&self, property: &str, value: Value, max: Option<usize>) -> Value {
+        let Some(mut current) = value.as_str().and_then(qid_number) else {
+            return value;
+        };
+        let parents = self.parents.get(property);
+        let max = max.unwrap_or(MAX_CHAIN_LENGTH).min(MAX_CHAIN_LENGTH);
+        let mut visited = HashSet::from([current]);
+        let mut chain = vec![value];
+        while chain.len() < max {
+            match parents.and_then(|parents| parents.get(&current)) {
+                Some(&parent) if visited.insert(parent) => {
+                    chain.push(Value::String(format!("Q{}", parent)));
+                    current = parent;
+                }
+                _ => break,
+            }
+        }
+        Value::Array(chain)
+    }
+}
@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::parse_report::SkipCounts;
+use crate::processing_error::ProcessingError;
+
+/// Progress of a byte range of the input, consistent with the output written so far
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RangeCheckpoint {
+    pub start: u64,
+    pub end: u64,
+    /// Offset of the first line that has not been processed yet
+    pub offset: u64,
+    /// Number of lines before the offset, and how many of them could not be parsed
+    #[serde(default)]
+    pub lines: u64,
+    #[serde(default)]
+    pub malformed: u64,
+    /// Number of entities before the offset that were skipped, for each reason
+    #[serde(default)]
+    pub skipped: SkipCounts,
+    /// Lengths of the output files at the time the offset was reached
+    pub output_lengths: Vec<u64>,
+}
+
+/// State of an extraction run from which it can be resumed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Input file and its size, to make sure a run is resumed on the same dump
+    pub input: String,
+    pub input_size: u64,
+    /// Output files that belong to each range
+    pub output_files: Vec<String>,
+    pub ranges: Vec<RangeCheckpoint>,
+}
+
+impl Checkpoint {
+    /// Checkpoint for a new run, in which nothing has been processed yet
+    pub fn new(
+        input: &str,
+        input_size: u64,
+        output_files: Vec<String>,
+        ranges: impl IntoIterator<Item = Range<u64>>,
+    ) -> Self {
+        let ranges = ranges
+            .into_iter()
+            .map(|range| RangeCheckpoint {
+                start: range.start,
+                end: range.end,
+                offset: range.start,
+                lines: 0,
+                malformed: 0,
+                skipped: SkipCounts::default(),
+                output_lengths: vec![0; output_files.len()],
+            })
+            .collect();
+        Self {
+            input: input.to_string(),
+            input_size,
+            output_files,
+            ranges,
+        }
+    }
+
+    /// Load the checkpoint of a previous run, and check that it matches the current run
+    pub fn load(
+        path: &Path,
+        input: &str,
+        input_size: u64,
+        output_files: &[String],
+    ) -> Result<Self, ProcessingError> {
+        if !path.exists() {
+            return Err(ProcessingError::CheckpointError(format!(
+                "no checkpoint found at {}",
+                path.display()
+            )));
+        }
+        let checkpoint: Checkpoint = serde_json::from_reader(File::open(path)?)?;
+        if checkpoint.input != input || checkpoint.input_size != input_size {
+            return Err(ProcessingError::CheckpointError(format!(
+                "checkpoint was made for {} ({} bytes), not for {} ({} bytes)",
+                checkpoint.input, checkpoint.input_size, input, input_size
+            )));
+        }
+        if checkpoint.output_files != output_files {
+            return Err(ProcessingError::CheckpointError(format!(
+                "checkpoint was made for the output files {:?}, not for {:?}",
+                checkpoint.output_files, output_files
+            )));
+        }
+        Ok(checkpoint)
+    }
+}
+
+/// Keeps track of the progress of all ranges, and periodically saves it to disk
+pub struct Checkpointer {
+    path: PathBuf,
+    checkpoint: Mutex<Checkpoint>,
+}
+
+impl Checkpointer {
+    pub fn new(path: PathBuf, checkpoint: Checkpoint) -> Self {
+        Self {
+            path,
+            checkpoint: Mutex::new(checkpoint),
+        }
+    }
+
+    /// State of a range when the checkpointer was created, or when it was last recorded
+    pub fn range(&self, index: usize) -> RangeCheckpoint {
+        self.checkpoint.lock().unwrap().ranges[index].clone()
+    }
+
+    /// State of all ranges
+    pub fn ranges(&self) -> Vec<RangeCheckpoint> {
+        self.checkpoint.lock().unwrap().ranges.clone()
+    }
+
+    /// Record the progress of a range, after its output has been flushed, and save the checkpoint
+    pub fn record(&self, index: usize, range: RangeCheckpoint) -> Result<(), ProcessingError> {
+        let mut checkpoint = self.checkpoint.lock().unwrap();
+        checkpoint.ranges[index] = range;
+        self.save(&checkpoint)
+    }
+
+    /// Write the checkpoint to a temporary file first, so a crash never leaves a partial checkpoint
+    fn save(&self, checkpoint: &Checkpoint) -> Result<(), ProcessingError> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec_pretty(checkpoint)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Save the current state
+    pub fn save_current(&self) -> Result<(), ProcessingError> {
+        let checkpoint = self.checkpoint.lock().unwrap();
+        self.save(&checkpoint)
+    }
+
+    /// Remove the checkpoint once the run has completed
+    pub fn remove(&self) -> Result<(), ProcessingError> {
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
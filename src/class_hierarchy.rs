@@ -0,0 +1,294 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::definitions::EntityTypeDefinition;
+use crate::processing_error::ProcessingError;
+use crate::wikidata_entity::WikidataEntity;
+
+/// Settings that determine the closure, stored in the cache to detect when it is outdated
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ClosureSettings {
+    input: String,
+    input_size: u64,
+    max_depth: Option<u32>,
+    excluded: Vec<String>,
+}
+
+/// Closure of an entity type, together with the classes and exclusions it was computed for
+#[derive(Debug, Serialize, Deserialize)]
+struct EntityTypeClasses {
+    classes: Vec<String>,
+    exclude: Vec<String>,
+    subclasses: Vec<String>,
+}
+
+/// Cached closure: all transitive subclasses of the classes of each entity type
+#[derive(Debug, Serialize, Deserialize)]
+struct ClassCache {
+    #[serde(flatten)]
+    settings: ClosureSettings,
+    entity_types: HashMap<String, EntityTypeClasses>,
+}
+
+/// The P279 (subclass of) graph of all classes in the dump, by numeric QID
+pub struct ClassHierarchy {
+    /// Direct subclasses of each class
+    subclasses: HashMap<u64, Vec<u64>>,
+}
+
+/// Numeric part of a QID, e.g. 5 for Q5
+pub fn qid_number(qid: &str) -> Option<u64> {
+    qid.strip_prefix('Q')?.parse().ok()
+}
+
+/// The subclass-of edges (subclass, class) of an entity
+pub fn subclass_edges(entity: &WikidataEntity) -> Vec<(u64, u64)> {
+    let Some(subclass) = qid_number(entity.id) else {
+        return Vec::new();
+    };
+    entity
+        .subclass_of()
+        .into_iter()
+        .filter_map(qid_number)
+        .map(|class| (subclass, class))
+        .collect()
+}
+
+impl ClassHierarchy {
+    /// The hierarchy of the subclass-of edges (subclass, class) of all entities
+    pub fn from_edges(edges: impl IntoIterator<Item = (u64, u64)>) -> Self {
+        let mut subclasses: HashMap<u64, Vec<u64>> = HashMap::new();
+        for (subclass, class) in edges {
+            subclasses.entry(class).or_default().push(subclass);
+        }
+        Self { subclasses }
+    }
+
+    /// The root class and all its transitive subclasses, up to the maximum depth (the root
+    /// being at depth 0). Excluded classes are not traversed, so their subclasses are only
+    /// included when they can be reached along another path.
+    pub fn closure(
+        &self,
+        root: u64,
+        max_depth: Option<u32>,
+        excluded: &HashSet<u64>,
+    ) -> HashSet<u64> {
+        let mut classes = HashSet::from([root]);
+        let mut frontier = vec![root];
+        let mut depth = 0;
+        while !frontier.is_empty() && max_depth.is_none_or(|max_depth| depth < max_depth) {
+            depth += 1;
+            frontier = frontier
+                .iter()
+                .flat_map(|class| self.subclasses.get(class).into_iter().flatten())
+                .copied()
+                .filter(|class| !excluded.contains(class) && classes.insert(*class))
+                .collect();
+        }
+        classes
+    }
+}
+
+/// Classes of the requested entity types: their P31 classes, and with `--subclasses` all their
+/// transitive subclasses
+pub type EntityMappings = HashMap<String, HashSet<String>>;
+
+/// The requested entity types with their definitions
+fn requested_definitions(config: &Config) -> Vec<(&String, &EntityTypeDefinition)> {
+    config
+        .entity_types
+        .iter()
+        .filter_map(|entity_type| Some((entity_type, config.definitions.get(entity_type)?)))
+        .collect()
+}
+
+fn closure_settings(
+    input_path: &Path,
+    config: &Config,
+) -> Result<ClosureSettings, ProcessingError> {
+    Ok(ClosureSettings {
+        input: input_path.to_string_lossy().to_string(),
+        input_size: fs::metadata(input_path)?.len(),
+        max_depth: config.max_depth,
+        excluded: config.exclude_classes.clone(),
+    })
+}
+
+fn class_cache_path(config: &Config) -> PathBuf {
+    PathBuf::from(
+        config
+            .class_cache
+            .clone()
+            .unwrap_or_else(|| format!("{}/class_hierarchy.json", config.output_dir)),
+    )
+}
+
+/// The entity types with the subclasses of the class cache
+fn entity_mappings(config: &Config, cache: &ClassCache) -> EntityMappings {
+    requested_definitions(config)
+        .iter()
+        .map(|(entity_type, definition)| {
+            // Classes that are not QIDs cannot be in the hierarchy, but still match directly
+            let classes = cache.entity_types[*entity_type]
+                .subclasses
+                .iter()
+                .chain(&definition.classes)
+                .cloned()
+                .collect();
+            (entity_type.to_string(), classes)
+        })
+        .collect()
+}
+
+/// The entity types with only their own P31 classes
+pub fn direct_entity_types(config: &Config) -> EntityMappings {
+    requested_definitions(config)
+        .iter()
+        .map(|(entity_type, definition)| {
+            let classes = definition.classes.iter().cloned().collect();
+            (entity_type.to_string(), classes)
+        })
+        .collect()
+}
+
+/// The entity types expanded to their transitive subclasses from the class cache, when the
+/// cache matches the current settings. Otherwise the class hierarchy has to be read from the dump.
+pub fn cached_entity_types(
+    input_path: &Path,
+    config: &Config,
+) -> Result<Option<EntityMappings>, ProcessingError> {
+    let settings = closure_settings(input_path, config)?;
+    let cache_path = class_cache_path(config);
+    let cache = File::open(&cache_path)
+        .ok()
+        .and_then(|file| serde_json::from_reader::<_, ClassCache>(BufReader::new(file)).ok())
+        .filter(|cache| {
+            cache.settings == settings
+                && requested_definitions(config)
+                    .iter()
+                    .all(|(entity_type, definition)| {
+                        cache.entity_types.get(*entity_type).is_some_and(|cached| {
+                            cached.classes == definition.classes
+                                && cached.exclude == definition.exclude
+                        })
+                    })
+        });
+    Ok(cache.map(|cache| {
+        println!("Using the class hierarchy in {}", cache_path.display());
+        entity_mappings(config, &cache)
+    }))
+}
+
+/// Expand the P31 classes of the requested entity types to all their transitive subclasses in
+/// the class hierarchy, and update the class cache
+pub fn expand_entity_types(
+    hierarchy: &ClassHierarchy,
+    input_path: &Path,
+    config: &Config,
+) -> Result<EntityMappings, ProcessingError> {
+    let entity_types = requested_definitions(config)
+        .iter()
+        .map(|(entity_type, definition)| {
+            let excluded: HashSet<u64> = config
+                .exclude_classes
+                .iter()
+                .chain(&definition.exclude)
+                .filter_map(|qid| qid_number(qid))
+                .collect();
+            let mut subclasses: Vec<u64> = definition
+                .classes
+                .iter()
+                .filter_map(|class| qid_number(class))
+                .flat_map(|class| hierarchy.closure(class, config.max_depth, &excluded))
+                .collect::<HashSet<u64>>()
+                .into_iter()
+                .collect();
+            subclasses.sort_unstable();
+            let classes = EntityTypeClasses {
+                classes: definition.classes.clone(),
+                exclude: definition.exclude.clone(),
+                subclasses: subclasses
+                    .iter()
+                    .map(|class| format!("Q{}", class))
+                    .collect(),
+            };
+            (entity_type.to_string(), classes)
+        })
+        .collect();
+    let cache = ClassCache {
+        settings: closure_settings(input_path, config)?,
+        entity_types,
+    };
+    // Write to a temporary file first, so an interrupted run never leaves a partial cache
+    let cache_path = class_cache_path(config);
+    let tmp_path = cache_path.with_extension("json.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer(&mut writer, &cache)?;
+    writer.flush()?;
+    fs::rename(&tmp_path, &cache_path)?;
+    Ok(entity_mappings(config, &cache))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hierarchy of (subclass, class) edges
+    fn hierarchy(edges: &[(u64, u64)]) -> ClassHierarchy {
+        ClassHierarchy::from_edges(edges.iter().copied())
+    }
+
+    fn sorted(classes: HashSet<u64>) -> Vec<u64> {
+        let mut classes: Vec<u64> = classes.into_iter().collect();
+        classes.sort_unstable();
+        classes
+    }
+
+    // 1 <- 2 <- 3 <- 4, and 1 <- 5 <- 6
+    const EDGES: [(u64, u64); 5] = [(2, 1), (3, 2), (4, 3), (5, 1), (6, 5)];
+
+    #[test]
+    fn includes_all_transitive_subclasses() {
+        let closure = hierarchy(&EDGES).closure(1, None, &HashSet::new());
+        assert_eq!(sorted(closure), vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn stops_at_the_maximum_depth() {
+        let hierarchy = hierarchy(&EDGES);
+        assert_eq!(
+            sorted(hierarchy.closure(1, Some(0), &HashSet::new())),
+            vec![1]
+        );
+        assert_eq!(
+            sorted(hierarchy.closure(1, Some(2), &HashSet::new())),
+            vec![1, 2, 3, 5, 6]
+        );
+    }
+
+    #[test]
+    fn skips_excluded_branches_unless_reachable_otherwise() {
+        let hierarchy = hierarchy(&EDGES);
+        let excluded = HashSet::from([2]);
+        assert_eq!(sorted(hierarchy.closure(1, None, &excluded)), vec![1, 5, 6]);
+        // 3 is also a subclass of 6, so it is reached along that path
+        let hierarchy = self::hierarchy(&[EDGES.as_slice(), &[(3, 6)]].concat());
+        assert_eq!(
+            sorted(hierarchy.closure(1, None, &excluded)),
+            vec![1, 3, 4, 5, 6]
+        );
+    }
+
+    #[test]
+    fn terminates_on_cycles() {
+        let hierarchy = hierarchy(&[(2, 1), (3, 2), (1, 3)]);
+        assert_eq!(
+            sorted(hierarchy.closure(1, None, &HashSet::new())),
+            vec![1, 2, 3]
+        );
+    }
+}
@@ -2,24 +2,116 @@ use clap::{Arg, ArgAction, Command};
 use std::fs::create_dir_all;
 use std::path::Path;
 
+use crate::definitions::Definitions;
 use crate::processing_error::ProcessingError;
+use crate::units::UnitConversions;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     /// P31 instance of entity types to extract
     pub entity_types: Vec<String>,
-    /// Preferred language for the results
-    pub lang: String,
+    /// Definitions of the entity types
+    pub definitions: Definitions,
+    /// Languages of the labels, descriptions and aliases, the first one being the primary language
+    pub languages: Vec<String>,
+    /// Languages to fall back to, in order, for terms that are missing in a language
+    pub fallback: Vec<String>,
+    /// All languages of which terms are used: the languages and their fallback languages
+    pub term_languages: Vec<String>,
     /// Output format, either JSONL or MessagePack
     pub output_format: String,
     /// Output directory, will be created automatically if it doesn't exist
     pub output_dir: String,
     /// Extract images as base64 strings. If not, only the image filename is returned.
     pub process_images: bool,
+    /// Resume from the checkpoint of a previous run
+    pub resume: bool,
+    /// Seconds between checkpoints
+    pub checkpoint_interval: u64,
+    /// Write the lines that could not be parsed to rejects.jsonl
+    pub rejects: bool,
+    /// Abort when more lines than this could not be parsed
+    pub max_errors: Option<u64>,
+    /// Verify the dump against the md5sums or sha1sums file next to it
+    pub verify_checksum: bool,
+    /// Also match entities that are an instance of a transitive subclass (P279) of an entity type
+    pub subclasses: bool,
+    /// Maximum depth of the subclasses below an entity type
+    pub max_depth: Option<u32>,
+    /// Classes whose subclasses are not matched
+    pub exclude_classes: Vec<String>,
+    /// File in which the subclasses are cached, by default class_hierarchy.json in the output directory
+    pub class_cache: Option<String>,
+    /// File in which the chains and coordinates are cached, by default lookups.msgpack in the output directory
+    pub lookup_cache: Option<String>,
+    /// Conversions of quantities to canonical units
+    pub unit_conversions: UnitConversions,
+    /// Also write the entities with coordinates as GeoJSON (FeatureCollection or NDJSON)
+    pub geojson: Option<String>,
+    /// Properties to include in the GeoJSON features, all properties when empty
+    pub geojson_properties: Vec<String>,
+    /// How the IDs in the properties are resolved to labels: with the Wikidata API (online), or
+    /// afterwards from a table of the labels in the dump itself (offline)
+    pub resolver: String,
+    /// Seconds before a request to the Wikidata API times out
+    pub request_timeout: u64,
+    /// Number of times a failed request to the Wikidata API is retried
+    pub max_retries: u32,
+    /// Seconds of replication lag at which the Wikidata API should refuse requests (maxlag)
+    pub maxlag: u32,
+    /// Maximum number of requests to the Wikidata API at the same time
+    pub concurrent_requests: usize,
+    /// Resolving deferred IDs, write the labels to a label table instead of rewriting the output
+    pub label_table: bool,
+    /// User-Agent of the requests, with contact information as the Wikimedia policy requires
+    pub user_agent: String,
 }
 
-/// Get the input file and additional configuration settings
-pub fn get_configuration() -> Result<(String, Config), ProcessingError> {
+/// The fallback chain of a language: the language itself, followed by the fallback languages
+pub fn language_chain<'a>(lang: &'a str, fallback: &'a [String]) -> impl Iterator<Item = &'a str> {
+    std::iter::once(lang).chain(
+        fallback
+            .iter()
+            .map(String::as_str)
+            .filter(move |fallback| *fallback != lang),
+    )
+}
+
+/// All languages of which terms are used: the languages and their fallback languages
+pub fn term_languages(languages: &[String], fallback: &[String]) -> Vec<String> {
+    let mut term_languages = languages.to_vec();
+    for lang in fallback {
+        if !term_languages.contains(lang) {
+            term_languages.push(lang.clone());
+        }
+    }
+    term_languages
+}
+
+/// The first value along the fallback chain of a language, with the language it is in.
+/// The values are in the order of the term languages.
+pub fn first_in_chain<'a, T>(
+    fallback: &'a [String],
+    term_languages: &[String],
+    values: &'a [Option<T>],
+    lang: &'a str,
+) -> Option<(&'a T, &'a str)> {
+    language_chain(lang, fallback).find_map(|chain_lang| {
+        let index = term_languages
+            .iter()
+            .position(|term_lang| term_lang == chain_lang)?;
+        Some((values[index].as_ref()?, chain_lang))
+    })
+}
+
+/// Name and version of this client, for the User-Agent
+const CLIENT_NAME: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+/// Name and version of the HTTP library, for the User-Agent
+const LIBRARY_NAME: &str = "reqwest/0.12";
+
+/// Get the input file, which is absent when resolving deferred IDs, and additional
+/// configuration settings
+pub fn get_configuration() -> Result<(Option<String>, Config), ProcessingError> {
     let matches = Command::new("Wikidata Entity Extraction")
       .version("1.0")
       .author("Erik Vullings")
@@ -27,15 +119,25 @@ pub fn get_configuration() -> Result<(String, Config), ProcessingError> {
       .arg(Arg::new("entity_types")
           .short('e')
           .long("entity-types")
-          .help("Comma-separated list of entity types to process (e.g., person, organization, location)")
-          .default_value("person,organization,scientific_organization,research_institute,government_agency,event,mood")
+          .help("Comma-separated list of entity types to process (e.g., person, organization, location) [default: all defined types]")
           .value_delimiter(',')
           .num_args(1..))
+      .arg(Arg::new("definitions")
+          .short('d')
+          .long("definitions")
+          .help("TOML file with the entity type definitions [default: the built-in definitions]"))
       .arg(Arg::new("lang")
           .short('l')
           .long("lang")
-          .help("Language for labels and descriptions")
+          .help("Comma-separated list of languages for labels, descriptions and aliases, e.g. en,nl,de")
+          .value_delimiter(',')
+          .num_args(1..)
           .default_value("en"))
+      .arg(Arg::new("fallback")
+          .long("fallback")
+          .help("Comma-separated list of languages to fall back to, in order, when a label, description or aliases are missing in a language, e.g. mul,en (mul: multiple languages) [default: none]")
+          .value_delimiter(',')
+          .num_args(1..))
       .arg(Arg::new("output_format")
           .short('f')
           .long("format")
@@ -48,22 +150,150 @@ pub fn get_configuration() -> Result<(String, Config), ProcessingError> {
           .default_value("output"))
       .arg(Arg::new("input_file")
           .help("Path to the Wikidata JSON dump")
-          .required(true)
+          .required_unless_present("resolve")
           .index(1))
+      .arg(Arg::new("resolve")
+          .long("resolve")
+          .help("Instead of extracting, resolve the IDs that an extraction with --resolver deferred kept in the output directory, with the --lang, --fallback, --format and --geojson of the extraction")
+          .conflicts_with("input_file")
+          .action(ArgAction::SetTrue)
+          .default_value("false"))
+      .arg(Arg::new("label_table")
+          .long("label-table")
+          .help("With --resolve, write the labels of the referenced IDs to output/labels.csv instead of replacing the IDs in the output")
+          .action(ArgAction::SetTrue)
+          .default_value("false"))
       .arg(Arg::new("process_images")
           .short('i')
           .long("process-images")
           .help("Process images")
           .action(ArgAction::SetTrue) // This makes it a flag, not requiring a value
           .default_value("false"))
+      .arg(Arg::new("resume")
+          .short('r')
+          .long("resume")
+          .help("Resume from the last checkpoint of an interrupted run")
+          .action(ArgAction::SetTrue)
+          .default_value("false"))
+      .arg(Arg::new("checkpoint_interval")
+          .long("checkpoint-interval")
+          .help("Seconds between checkpoints")
+          .value_parser(clap::value_parser!(u64))
+          .default_value("300"))
+      .arg(Arg::new("rejects")
+          .long("rejects")
+          .help("Write the lines that could not be parsed to rejects.jsonl in the output directory")
+          .action(ArgAction::SetTrue)
+          .default_value("false"))
+      .arg(Arg::new("max_errors")
+          .long("max-errors")
+          .help("Abort when more than this number of lines could not be parsed")
+          .value_parser(clap::value_parser!(u64)))
+      .arg(Arg::new("verify_checksum")
+          .long("verify-checksum")
+          .help("Verify the dump against the md5sums or sha1sums file in the same directory")
+          .action(ArgAction::SetTrue)
+          .default_value("false"))
+      .arg(Arg::new("subclasses")
+          .short('s')
+          .long("subclasses")
+          .help("Also match instances of (transitive) subclasses of the entity types, which requires a first pass over the dump")
+          .action(ArgAction::SetTrue)
+          .default_value("false"))
+      .arg(Arg::new("max_depth")
+          .long("max-depth")
+          .help("Maximum depth of the subclasses below an entity type")
+          .value_parser(clap::value_parser!(u32)))
+      .arg(Arg::new("exclude_classes")
+          .long("exclude-classes")
+          .help("Comma-separated list of classes (QIDs) whose subclasses are not matched")
+          .value_delimiter(',')
+          .num_args(1..))
+      .arg(Arg::new("class_cache")
+          .long("class-cache")
+          .help("File in which the subclasses are cached [default: class_hierarchy.json in the output directory]"))
+      .arg(Arg::new("lookup_cache")
+          .long("lookup-cache")
+          .help("File in which the values of chained properties and the coordinates of locations are cached [default: lookups.msgpack in the output directory]"))
+      .arg(Arg::new("unit_conversions")
+          .long("unit-conversions")
+          .help("TOML file with conversions of quantities to canonical units, e.g. data/unit_conversions.toml"))
+      .arg(Arg::new("geojson")
+          .long("geojson")
+          .help("Also write the entities with coordinates as GeoJSON, which requires a first pass over the dump to locate entities by their headquarters or location")
+          .value_parser(["FeatureCollection", "NDJSON"]))
+      .arg(Arg::new("geojson_properties")
+          .long("geojson-properties")
+          .help("Comma-separated list of the properties (output keys) to include in the GeoJSON features [default: all]")
+          .value_delimiter(',')
+          .num_args(1..))
+      .arg(Arg::new("resolver")
+          .long("resolver")
+          .help("Resolve the IDs in the properties to labels with the Wikidata API, offline from the labels in the dump itself, written to output/labels.csv, or deferred to a separate run with --resolve")
+          .value_parser(["online", "offline", "deferred"])
+          .default_value("online"))
+      .arg(Arg::new("request_timeout")
+          .long("request-timeout")
+          .help("Seconds before a request to the Wikidata API times out")
+          .value_parser(clap::value_parser!(u64))
+          .default_value("30"))
+      .arg(Arg::new("max_retries")
+          .long("max-retries")
+          .help("Number of times a failed request to the Wikidata API is retried, with exponential backoff")
+          .value_parser(clap::value_parser!(u32))
+          .default_value("5"))
+      .arg(Arg::new("maxlag")
+          .long("maxlag")
+          .help("Seconds of database replication lag at which the Wikidata API asks to retry later")
+          .value_parser(clap::value_parser!(u32))
+          .default_value("5"))
+      .arg(Arg::new("concurrent_requests")
+          .long("concurrent-requests")
+          .help("Maximum number of requests to the Wikidata API at the same time, each for a batch of up to 50 IDs of all workers")
+          .value_parser(clap::value_parser!(usize))
+          .default_value("2"))
+      .arg(Arg::new("contact")
+          .long("contact")
+          .help("Contact information (e-mail address or URL) for the User-Agent of the requests to the Wikidata API, as required by the Wikimedia User-Agent policy. Required when resolving online and with --resolve"))
       .get_matches();
-    let entity_types: Vec<String> = matches
-        .get_many::<String>("entity_types")
-        .unwrap()
-        .map(|s| s.trim().to_string())
-        .filter(|s| !s.is_empty())
-        .collect();
-    let lang = matches.get_one::<String>("lang").unwrap().to_string();
+    let definitions = Definitions::load(matches.get_one::<String>("definitions").map(Path::new))?;
+    let entity_types: Vec<String> = match matches.get_many::<String>("entity_types") {
+        Some(entity_types) => entity_types
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect(),
+        None => definitions.names().map(String::from).collect(),
+    };
+    if let Some(unknown) = entity_types
+        .iter()
+        .find(|entity_type| definitions.get(entity_type).is_none())
+    {
+        return Err(ProcessingError::ConfigError(format!(
+            "unknown entity type '{}', the defined types are: {}",
+            unknown,
+            definitions.names().collect::<Vec<_>>().join(", ")
+        )));
+    }
+    let mut languages: Vec<String> = Vec::new();
+    for lang in matches.get_many::<String>("lang").unwrap_or_default() {
+        let lang = lang.trim().to_string();
+        if !lang.is_empty() && !languages.contains(&lang) {
+            languages.push(lang);
+        }
+    }
+    if languages.is_empty() {
+        return Err(ProcessingError::ConfigError(
+            "at least one language is required".to_string(),
+        ));
+    }
+    let mut fallback: Vec<String> = Vec::new();
+    for lang in matches.get_many::<String>("fallback").unwrap_or_default() {
+        let lang = lang.trim().to_string();
+        if !lang.is_empty() && !fallback.contains(&lang) {
+            fallback.push(lang);
+        }
+    }
+    let term_languages = term_languages(&languages, &fallback);
     let output_format = matches
         .get_one::<String>("output_format")
         .unwrap()
@@ -75,17 +305,185 @@ pub fn get_configuration() -> Result<(String, Config), ProcessingError> {
         .trim()
         .to_string();
     let process_images = matches.get_flag("process_images");
+    let resume = matches.get_flag("resume");
+    let checkpoint_interval = *matches.get_one::<u64>("checkpoint_interval").unwrap();
+    let rejects = matches.get_flag("rejects");
+    let max_errors = matches.get_one::<u64>("max_errors").copied();
+    let verify_checksum = matches.get_flag("verify_checksum");
+    let subclasses = matches.get_flag("subclasses");
+    let max_depth = matches.get_one::<u32>("max_depth").copied();
+    let exclude_classes: Vec<String> = matches
+        .get_many::<String>("exclude_classes")
+        .unwrap_or_default()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    let class_cache = matches.get_one::<String>("class_cache").cloned();
+    let lookup_cache = matches.get_one::<String>("lookup_cache").cloned();
+    let unit_conversions =
+        UnitConversions::load(matches.get_one::<String>("unit_conversions").map(Path::new))?;
+    let geojson = matches.get_one::<String>("geojson").cloned();
+    let geojson_properties: Vec<String> = matches
+        .get_many::<String>("geojson_properties")
+        .unwrap_or_default()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    let resolver = matches.get_one::<String>("resolver").unwrap().clone();
+    let request_timeout = *matches.get_one::<u64>("request_timeout").unwrap();
+    let max_retries = *matches.get_one::<u32>("max_retries").unwrap();
+    let maxlag = *matches.get_one::<u32>("maxlag").unwrap();
+    let concurrent_requests = *matches.get_one::<usize>("concurrent_requests").unwrap();
+    let label_table = matches.get_flag("label_table");
+    let input_file = matches.get_one::<String>("input_file").cloned();
+    // The Wikimedia User-Agent policy asks for contact information from clients of the API
+    let contact = matches
+        .get_one::<String>("contact")
+        .map(|contact| contact.trim())
+        .filter(|contact| !contact.is_empty());
+    let user_agent = match contact {
+        Some(contact) => format!("{} ({}) {}", CLIENT_NAME, contact, LIBRARY_NAME),
+        None if resolver == "online" || input_file.is_none() => {
+            return Err(ProcessingError::ConfigError(
+                "pass an e-mail address or URL with --contact to request labels from the Wikidata API, as its User-Agent policy asks, or use --resolver offline or deferred".to_string(),
+            ))
+        }
+        None => format!("{} {}", CLIENT_NAME, LIBRARY_NAME),
+    };
     let output_path = Path::new(&output_dir);
     if !output_path.exists() {
         create_dir_all(output_path)?;
     }
-    let input_file = matches.get_one::<String>("input_file").unwrap().to_string();
+    if label_table && input_file.is_some() {
+        return Err(ProcessingError::ConfigError(
+            "--label-table only applies to --resolve".to_string(),
+        ));
+    }
     let config = Config {
         entity_types,
-        lang,
+        definitions,
+        languages,
+        term_languages,
+        fallback,
         output_format,
         output_dir,
         process_images,
+        resume,
+        checkpoint_interval,
+        rejects,
+        max_errors,
+        verify_checksum,
+        subclasses,
+        max_depth,
+        exclude_classes,
+        class_cache,
+        lookup_cache,
+        unit_conversions,
+        geojson,
+        geojson_properties,
+        resolver,
+        request_timeout,
+        max_retries,
+        maxlag,
+        concurrent_requests,
+        label_table,
+        user_agent,
     };
     Ok((input_file, config))
 }
+
+#[cfg(test)]
+impl Config {
+    /// Configuration with the defaults of the command line, writing to an output directory
+    pub fn for_test(output_dir: &Path) -> Self {
+        let definitions = Definitions::load(None).unwrap();
+        Self {
+            entity_types: definitions.names().map(String::from).collect(),
+            definitions,
+            languages: vec!["en".to_string()],
+            fallback: Vec::new(),
+            term_languages: vec!["en".to_string()],
+            output_format: "MessagePack".to_string(),
+            output_dir: output_dir.to_string_lossy().to_string(),
+            process_images: false,
+            resume: false,
+            checkpoint_interval: 300,
+            rejects: false,
+            max_errors: None,
+            verify_checksum: false,
+            subclasses: false,
+            max_depth: None,
+            exclude_classes: Vec::new(),
+            class_cache: None,
+            lookup_cache: None,
+            unit_conversions: UnitConversions::default(),
+            geojson: None,
+            geojson_properties: Vec::new(),
+            resolver: "online".to_string(),
+            request_timeout: 30,
+            max_retries: 5,
+            maxlag: 5,
+            concurrent_requests: 2,
+            label_table: false,
+            user_agent: format!("{} {}", CLIENT_NAME, LIBRARY_NAME),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn follows_the_fallback_languages_after_the_language_itself() {
+        let fallback = strings(&["mul", "en"]);
+        assert_eq!(
+            language_chain("nl", &fallback).collect::<Vec<_>>(),
+            ["nl", "mul", "en"]
+        );
+        // A fallback language is not repeated
+        assert_eq!(
+            language_chain("en", &fallback).collect::<Vec<_>>(),
+            ["en", "mul"]
+        );
+        assert_eq!(language_chain("nl", &[]).collect::<Vec<_>>(), ["nl"]);
+    }
+
+    #[test]
+    fn adds_the_fallback_languages_to_the_term_languages() {
+        let languages = strings(&["nl", "en"]);
+        assert_eq!(
+            term_languages(&languages, &strings(&["mul", "en"])),
+            ["nl", "en", "mul"]
+        );
+        assert_eq!(term_languages(&languages, &[]), ["nl", "en"]);
+    }
+
+    #[test]
+    fn takes_the_first_value_along_the_chain() {
+        let fallback = strings(&["mul", "en"]);
+        let languages = term_languages(&strings(&["nl", "de"]), &fallback);
+        // Values of nl, de, mul and en
+        let values = [Some("Amsterdam"), None, None, Some("Amsterdam (en)")];
+        assert_eq!(
+            first_in_chain(&fallback, &languages, &values, "nl"),
+            Some((&"Amsterdam", "nl"))
+        );
+        assert_eq!(
+            first_in_chain(&fallback, &languages, &values, "de"),
+            Some((&"Amsterdam (en)", "en"))
+        );
+        assert_eq!(first_in_chain(&[], &languages, &values, "de"), None);
+        // A name that applies to all languages
+        let values = [None, None, Some("Q1"), Some("Q2")];
+        assert_eq!(
+            first_in_chain(&fallback, &languages, &values, "nl"),
+            Some((&"Q1", "mul"))
+        );
+        assert_eq!(first_in_chain(&[], &languages, &values, "nl"), None);
+    }
+}
@@ -0,0 +1,287 @@
+// Normalization of Wikibase points in time to ISO 8601 at their precision.
+// See https://www.wikidata.org/wiki/Help:Dates
+use serde_json::{json, Value};
+
+use crate::wikibase::TimeValue;
+
+/// Calendar model of dates in the proleptic Julian calendar
+const JULIAN_CALENDAR: &str = "http://www.wikidata.org/entity/Q1985786";
+
+/// Name of a Wikibase time precision
+fn precision_name(precision: u8) -> &'static str {
+    match precision {
+        0 => "billion years",
+        1 => "hundred million years",
+        2 => "ten million years",
+        3 => "million years",
+        4 => "hundred thousand years",
+        5 => "ten thousand years",
+        6 => "millennium",
+        7 => "century",
+        8 => "decade",
+        9 => "year",
+        10 => "month",
+        11 => "day",
+        12 => "hour",
+        13 => "minute",
+        _ => "second",
+    }
+}
+
+/// A parsed point in time, with an astronomical year (1 BCE is year 0, 2 BCE is year -1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Date {
+    year: i64,
+    month: u32,
+    day: u32,
+}
+
+/// Parse a Wikibase time like `+1879-03-14T00:00:00Z` or `-0044-03-15T00:00:00Z` into the
+/// date and the time of day. Wikibase has no year 0, so BCE years are shifted by one.
+fn parse_time(time: &str) -> Option<(Date, &str)> {
+    let (date, time_of_day) = time.split_once('T')?;
+    let (negative, date) = match date.as_bytes().first()? {
+        b'-' => (true, &date[1..]),
+        b'+' => (false, &date[1..]),
+        _ => (false, date),
+    };
+    let mut parts = date.rsplitn(3, '-');
+    let day = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let year = if negative { 1 - year } else { year };
+    Some((Date { year, month, day }, time_of_day))
+}
+
+/// Julian day number of a date in the proleptic Julian calendar
+fn julian_day_number(date: Date) -> i64 {
+    let a = (14 - date.month as i64) / 12;
+    let y = date.year + 4800 - a;
+    let m = date.month as i64 + 12 * a - 3;
+    date.day as i64 + (153 * m + 2) / 5 + 365 * y + y.div_euclid(4) - 32083
+}
+
+/// Date in the proleptic Gregorian calendar of a Julian day number
+fn gregorian_date(julian_day_number: i64) -> Date {
+    let a = julian_day_number + 32044;
+    let b = (4 * a + 3).div_euclid(146097);
+    let c = a - (146097 * b).div_euclid(4);
+    let d = (4 * c + 3) / 1461;
+    let e = c - 1461 * d / 4;
+    let m = (5 * e + 2) / 153;
+    Date {
+        year: 100 * b + d - 4800 + m / 10,
+        month: (m + 3 - 12 * (m / 10)) as u32,
+        day: (e - (153 * m + 2) / 5 + 1) as u32,
+    }
+}
+
+/// ISO 8601 year, with at least four digits and a sign for years before year 0
+fn iso_year(year: i64) -> String {
+    if year < 0 {
+        format!("-{:04}", -year)
+    } else {
+        format!("{:04}", year)
+    }
+}
+
+/// Key to order points in time chronologically, by their (astronomical) year, month and day
+pub fn chronological_key(time: &TimeValue) -> Option<(i64, u32, u32)> {
+    let (date, _) = parse_time(&time.time)?;
+    Some((date.year, date.month, date.day))
+}
+
+/// The ISO 8601 notation of the period of `length` years that contains an astronomical year:
+/// the leading digits of the year, e.g. `187` for the decade 1870 to 1879 and `19` for the
+/// century 1900 to 1999. Periods before year 0 are negative, e.g. `-01` for the years -100 to -1.
+fn iso_period(year: i64, length: i64, digits: usize) -> String {
+    match year.div_euclid(length) {
+        period if period < 0 => format!("-{:0digits$}", -period),
+        period => format!("{:0digits$}", period),
+    }
+}
+
+/// The ordinal of the period of `length` years that contains an astronomical year, like
+/// Wikidata counts centuries and millennia: 19 for 1801 to 1900, or 2 for 1001 to 2000.
+/// Periods before the common era are negative, e.g. -1 for 100 to 1 BCE (years -99 to 0).
+fn ordinal_period(year: i64, length: i64) -> i64 {
+    if year >= 1 {
+        (year + length - 1).div_euclid(length)
+    } else {
+        -(1 - year + length - 1).div_euclid(length)
+    }
+}
+
+/// Normalize a point in time to `{date, precision}`, where the date is written in ISO 8601 at
+/// the precision of the value:
+/// - second, minute and hour: `1879-03-14T00:00:00Z`
+/// - day: `1879-03-14`, month: `1879-03`, year: `1879`
+/// - decade, century and millennium: the leading digits of the year, e.g. `187` for 1870 to
+///   1879, `19` for 1900 to 1999 and `1` for 1000 to 1999
+/// - coarser precisions: the year
+///
+/// Centuries and millennia also have the `ordinal` that Wikidata shows, which counts from
+/// year 1, e.g. 19 for the 19th century (1801 to 1900) and -1 for the 1st century BCE.
+/// Julian dates of day precision or finer are converted to the proleptic Gregorian calendar.
+/// Coarser Julian dates are kept as they are, as converting them could change the year.
+/// Years BCE are astronomical, i.e. 1 BCE is `0000` and 44 BCE is `-0043`.
+pub fn normalize_time(time: &TimeValue) -> Option<Value> {
+    let (mut date, time_of_day) = parse_time(&time.time)?;
+    // Dates without a month or day have a coarser precision than stated
+    let precision = match time.precision {
+        precision if precision >= 10 && date.month == 0 => 9,
+        precision if precision >= 11 && date.day == 0 => 10,
+        precision => precision,
+    };
+    if precision >= 11 && time.calendarmodel == JULIAN_CALENDAR {
+        date = gregorian_date(julian_day_number(date));
+    }
+    let year = iso_year(date.year);
+    let iso = match precision {
+        0..=5 => year,
+        6 => iso_period(date.year, 1000, 1),
+        7 => iso_period(date.year, 100, 2),
+        8 => iso_period(date.year, 10, 3),
+        9 => year,
+        10 => format!("{}-{:02}", year, date.month),
+        11 => format!("{}-{:02}-{:02}", year, date.month, date.day),
+        _ => format!("{}-{:02}-{:02}T{}", year, date.month, date.day, time_of_day),
+    };
+    let mut value = json!({
+        "date": iso,
+        "precision": precision_name(precision),
+    });
+    if let 6 | 7 = precision {
+        let length = if precision == 6 { 1000 } else { 100 };
+        value["ordinal"] = json!(ordinal_period(date.year, length));
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GREGORIAN_CALENDAR: &str = "http://www.wikidata.org/entity/Q1985727";
+
+    fn normalize(time: &str, precision: u8, calendarmodel: &str) -> (String, String) {
+        let value = normalize_time(&TimeValue {
+            time: time.to_string(),
+            timezone: 0,
+            before: 0,
+            after: 0,
+            precision,
+            calendarmodel: calendarmodel.to_string(),
+        })
+        .unwrap();
+        (
+            value["date"].as_str().unwrap().to_string(),
+            value["precision"].as_str().unwrap().to_string(),
+        )
+    }
+
+    #[test]
+    fn formats_dates_at_their_precision() {
+        let date = |time, precision| normalize(time, precision, GREGORIAN_CALENDAR).0;
+        assert_eq!(date("+1879-03-14T00:00:00Z", 11), "1879-03-14");
+        assert_eq!(date("+1879-03-00T00:00:00Z", 10), "1879-03");
+        assert_eq!(date("+1500-00-00T00:00:00Z", 9), "1500");
+        assert_eq!(date("+1875-00-00T00:00:00Z", 8), "187");
+        assert_eq!(date("+1850-00-00T00:00:00Z", 7), "18");
+        assert_eq!(date("+2000-00-00T00:00:00Z", 6), "2");
+        assert_eq!(date("+1879-03-14T12:30:00Z", 14), "1879-03-14T12:30:00Z");
+    }
+
+    #[test]
+    fn writes_centuries_and_millennia_as_leading_digits_of_the_year() {
+        let date = |time, precision| normalize(time, precision, GREGORIAN_CALENDAR).0;
+        assert_eq!(date("+1800-00-00T00:00:00Z", 7), "18");
+        assert_eq!(date("+1801-00-00T00:00:00Z", 7), "18");
+        assert_eq!(date("+1900-00-00T00:00:00Z", 7), "19");
+        assert_eq!(date("+1999-00-00T00:00:00Z", 7), "19");
+        assert_eq!(date("+0001-00-00T00:00:00Z", 7), "00");
+        assert_eq!(date("+1000-00-00T00:00:00Z", 6), "1");
+        assert_eq!(date("+1999-00-00T00:00:00Z", 6), "1");
+        assert_eq!(date("+2001-00-00T00:00:00Z", 6), "2");
+        // Before year 0: 2 to 101 BCE are the astronomical years -100 to -1
+        assert_eq!(date("-0001-00-00T00:00:00Z", 7), "00");
+        assert_eq!(date("-0002-00-00T00:00:00Z", 7), "-01");
+        assert_eq!(date("-0101-00-00T00:00:00Z", 7), "-01");
+        assert_eq!(date("-0102-00-00T00:00:00Z", 7), "-02");
+        assert_eq!(date("-1001-00-00T00:00:00Z", 6), "-1");
+    }
+
+    #[test]
+    fn counts_centuries_and_millennia_as_ordinals() {
+        let ordinal = |time: &str, precision| {
+            normalize_time(&TimeValue {
+                time: time.to_string(),
+                timezone: 0,
+                before: 0,
+                after: 0,
+                precision,
+                calendarmodel: GREGORIAN_CALENDAR.to_string(),
+            })
+            .unwrap()["ordinal"]
+                .clone()
+        };
+        assert_eq!(ordinal("+1800-00-00T00:00:00Z", 7), 18);
+        assert_eq!(ordinal("+1801-00-00T00:00:00Z", 7), 19);
+        assert_eq!(ordinal("+1900-00-00T00:00:00Z", 7), 19);
+        assert_eq!(ordinal("+1901-00-00T00:00:00Z", 7), 20);
+        assert_eq!(ordinal("+1000-00-00T00:00:00Z", 6), 1);
+        assert_eq!(ordinal("+1001-00-00T00:00:00Z", 6), 2);
+        // Before the common era: 1 to 100 BCE is the 1st century BCE
+        assert_eq!(ordinal("-0001-00-00T00:00:00Z", 7), -1);
+        assert_eq!(ordinal("-0100-00-00T00:00:00Z", 7), -1);
+        assert_eq!(ordinal("-0101-00-00T00:00:00Z", 7), -2);
+        assert_eq!(ordinal("-1001-00-00T00:00:00Z", 6), -2);
+        // Only centuries and millennia have an ordinal
+        assert_eq!(ordinal("+1875-00-00T00:00:00Z", 8), Value::Null);
+        assert_eq!(ordinal("+1875-00-00T00:00:00Z", 9), Value::Null);
+    }
+
+    #[test]
+    fn writes_decades_before_common_era_with_three_digits() {
+        let date = |time, precision| normalize(time, precision, GREGORIAN_CALENDAR).0;
+        // 51 BCE is the astronomical year -50
+        assert_eq!(date("-0051-00-00T00:00:00Z", 8), "-005");
+        assert_eq!(date("-0044-00-00T00:00:00Z", 8), "-005");
+        assert_eq!(date("-0001-00-00T00:00:00Z", 8), "000");
+        assert_eq!(date("-0002-00-00T00:00:00Z", 8), "-001");
+        assert_eq!(date("-1235-00-00T00:00:00Z", 8), "-124");
+    }
+
+    #[test]
+    fn lowers_precision_of_missing_month_or_day() {
+        let normalized = normalize("+1500-00-00T00:00:00Z", 11, GREGORIAN_CALENDAR);
+        assert_eq!(normalized, ("1500".to_string(), "year".to_string()));
+    }
+
+    #[test]
+    fn uses_astronomical_years_before_common_era() {
+        let date = |time, precision| normalize(time, precision, GREGORIAN_CALENDAR).0;
+        assert_eq!(date("-0001-00-00T00:00:00Z", 9), "0000");
+        assert_eq!(date("-0044-03-15T00:00:00Z", 11), "-0043-03-15");
+        assert_eq!(date("-13798000000-00-00T00:00:00Z", 0), "-13797999999");
+    }
+
+    #[test]
+    fn converts_julian_dates_to_gregorian() {
+        let julian = "http://www.wikidata.org/entity/Q1985786";
+        assert_eq!(
+            normalize("+1582-10-05T00:00:00Z", 11, julian).0,
+            "1582-10-15"
+        );
+        assert_eq!(
+            normalize("+1700-02-29T00:00:00Z", 11, julian).0,
+            "1700-03-11"
+        );
+        assert_eq!(
+            normalize("-0044-03-15T00:00:00Z", 11, julian).0,
+            "-0043-03-13"
+        );
+        // Coarser dates are not converted
+        assert_eq!(normalize("+1582-10-00T00:00:00Z", 10, julian).0, "1582-10");
+    }
+}
@@ -0,0 +1,381 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::batched_writer::OutputPaths;
+use crate::class_hierarchy::qid_number;
+use crate::config::{term_languages, Config};
+use crate::entity_resolver::EntityResolver;
+use crate::geojson::write_feature_collection;
+use crate::label_source::{LabelSource, MediaWikiApi, API_BATCH_SIZE};
+use crate::label_table::{referenced_ids, rewrite_properties, LabelTable};
+use crate::processing_error::ProcessingError;
+
+/// Number of batches between the progress messages of the resolve step
+const PROGRESS_INTERVAL: usize = 100;
+
+/// File with the IDs that are referenced in the properties, one per line
+fn referenced_ids_path(config: &Config) -> PathBuf {
+    PathBuf::from(format!("{}/referenced_ids.txt", config.output_dir))
+}
+
+/// The options of a deferred extraction that determine its output, which the resolve step reads
+/// back instead of taking them from its own command line
+#[derive(Debug, Serialize, Deserialize)]
+struct DeferredSettings {
+    output_format: String,
+    languages: Vec<String>,
+    fallback: Vec<String>,
+    geojson: Option<String>,
+    /// Whether the IDs in the output have been replaced with their labels already, without
+    /// network errors
+    resolved: bool,
+}
+
+impl DeferredSettings {
+    fn path(config: &Config) -> PathBuf {
+        PathBuf::from(format!("{}/deferred.json", config.output_dir))
+    }
+
+    fn of(config: &Config) -> Self {
+        Self {
+            output_format: config.output_format.clone(),
+            languages: config.languages.clone(),
+            fallback: config.fallback.clone(),
+            geojson: config.geojson.clone(),
+            resolved: false,
+        }
+    }
+
+    fn load(config: &Config) -> Result<Self, ProcessingError> {
+        let path = Self::path(config);
+        if !path.exists() {
+            return Err(ProcessingError::ConfigError(format!(
+                "{} does not exist, extract with --resolver deferred first",
+                path.display()
+            )));
+        }
+        Ok(serde_json::from_reader(BufReader::new(File::open(&path)?))?)
+    }
+
+    /// Save the settings via a temporary file, so they are never partially written
+    fn save(&self, config: &Config) -> Result<(), ProcessingError> {
+        let path = Self::path(config);
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    /// The configuration of the resolve step, with the options of the extraction
+    fn apply(&self, config: &Config) -> Config {
+        Config {
+            output_format: self.output_format.clone(),
+            languages: self.languages.clone(),
+            fallback: self.fallback.clone(),
+            term_languages: term_languages(&self.languages, &self.fallback),
+            geojson: self.geojson.clone(),
+            ..config.clone()
+        }
+    }
+}
+
+/// After a deferred extraction, which keeps the IDs in the properties, write the IDs that are
+/// referenced in the KV store, and the options the resolve step needs, for the resolve step
+pub fn write_referenced_ids(paths: &OutputPaths, config: &Config) -> Result<(), ProcessingError> {
+    if config.resolver != "deferred" {
+        return Ok(());
+    }
+    let mut ids: Vec<String> = referenced_ids(&paths.kv, &config.output_format)?
+        .into_iter()
+        .collect();
+    ids.sort_by_key(|id| qid_number(id));
+    let path = referenced_ids_path(config);
+    let mut writer = BufWriter::new(File::create(&path)?);
+    for id in &ids {
+        writeln!(writer, "{}", id)?;
+    }
+    writer.flush()?;
+    DeferredSettings::of(config).save(config)?;
+    println!(
+        "Wrote the {} referenced IDs to {}, resolve them with --resolve",
+        ids.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// The resolve step of a deferred extraction: get the labels of all referenced IDs in bulk,
+/// from the entity cache or in full batches from the Wikidata API, and rewrite the KV store
+/// and the GeoJSON features with them, or write them to a label table for consumers to join
+/// on. The cache is saved, so the labels are reused by later runs. The output is read with the
+/// options of the extraction. It is rewritten until none of the IDs failed to resolve, so the
+/// step can be repeated.
+pub fn resolve_deferred(config: &Config) -> Result<(), ProcessingError> {
+    let settings = DeferredSettings::load(config)?;
+    let config = settings.apply(config);
+    let source = MediaWikiApi::wikidata(&config)?;
+    resolve(&config, settings, Box::new(source))
+}
+
+/// Resolve the referenced IDs of a deferred extraction with the labels of a source
+fn resolve(
+    config: &Config,
+    mut settings: DeferredSettings,
+    source: Box<dyn LabelSource>,
+) -> Result<(), ProcessingError> {
+    let ids_path = referenced_ids_path(config);
+    if !ids_path.exists() {
+        return Err(ProcessingError::ConfigError(format!(
+            "{} does not exist, extract with --resolver deferred first",
+            ids_path.display()
+        )));
+    }
+    let ids = BufReader::new(File::open(&ids_path)?)
+        .lines()
+        .collect::<Result<Vec<String>, _>>()?;
+
+    let resolver = EntityResolver::new(
+        Some(PathBuf::from(format!(
+            "{}/entity_cache.csv",
+            config.output_dir
+        ))),
+        source,
+        &config.languages,
+    );
+    let uncached: Vec<&str> = ids
+        .iter()
+        .map(String::as_str)
+        .filter(|id| resolver.get_cached_labels(id).is_none())
+        .collect();
+    println!(
+        "Resolving {} referenced IDs, of which {} are not cached",
+        ids.len(),
+        uncached.len()
+    );
+
+    // Each thread requests the next batch, so at most the configured number of requests are
+    // sent at the same time
+    let batches: Vec<&[&str]> = uncached.chunks(API_BATCH_SIZE).collect();
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..config.concurrent_requests.max(1) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(batch) = batches.get(index) else {
+                    break;
+                };
+                resolver.prefetch(batch);
+                if (index + 1).is_multiple_of(PROGRESS_INTERVAL) {
+                    println!("Requested {} of {} batches", index + 1, batches.len());
+                }
+            });
+        }
+    });
+    resolver.save_cache();
+    if resolver.unresolved() > 0 {
+        println!(
+            "IDs that could not be resolved because of network errors: {}",
+            resolver.unresolved()
+        );
+    }
+
+    // Once written as a collection, by an earlier resolve step, the features are left as they are
+    let mut paths = OutputPaths::new(config, None);
+    paths.geojson = paths.geojson.filter(|features| features.exists());
+    if config.label_table {
+        let path = PathBuf::from(format!("{}/labels.csv", config.output_dir));
+        let mut writer = csv::Writer::from_path(&path)?;
+        for id in &ids {
+            let Some(labels) = resolver.get_cached_labels(id) else {
+                continue;
+            };
+            let labels: Vec<Option<&str>> = labels
+                .iter()
+                .map(|label| Some(label.as_str()).filter(|label| !label.is_empty()))
+                .collect();
+            for row in LabelTable::rows(id, &labels, &config.languages) {
+                writer.write_record(&row)?;
+            }
+        }
+        writer.flush()?;
+        println!("Wrote the labels to {}", path.display());
+    } else if settings.resolved {
+        println!("The IDs in {} are resolved already", paths.kv.display());
+    } else {
+        // The labels are all cached now, so the IDs that could not be resolved are kept, and
+        // are resolved by the next run, unless they are unknown
+        rewrite_properties(&paths, &config.output_format, |properties| {
+            resolver.resolve_cached(properties)
+        })?;
+        if resolver.unresolved() == 0 {
+            settings.resolved = true;
+            settings.save(config)?;
+        }
+        println!("Resolved the IDs in {}", paths.kv.display());
+    }
+
+    // The features are only written as a collection once they are resolved, as they cannot be
+    // rewritten afterwards
+    if let (Some(features), Some("FeatureCollection"), true) = (
+        &paths.geojson,
+        config.geojson.as_deref(),
+        settings.resolved || config.label_table,
+    ) {
+        write_feature_collection(features)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::label_source::MemorySource;
+    use serde_json::{json, Value};
+
+    /// The labels that the source knows: Q3 has no label, and Q404 is unknown
+    const LABELS: &[(&str, &[&str])] = &[
+        ("Q55", &["Netherlands"]),
+        ("Q2", &["Ada Example"]),
+        ("Q3", &[""]),
+    ];
+
+    /// Write the output of a deferred extraction to an empty output directory, and return the
+    /// options of the resolve step, which leave out the options of the extraction
+    fn extract_deferred(name: &str) -> Config {
+        let dir = std::env::temp_dir().join(format!("deferred_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut config = Config::for_test(&dir);
+        config.output_format = "JSONLines".to_string();
+        config.resolver = "deferred".to_string();
+        config.geojson = Some("FeatureCollection".to_string());
+        let paths = OutputPaths::new(&config, None);
+
+        let entry = json!({"Q1": {
+            "label": "Acme",
+            "props": {"P17": "Q55", "P112": ["Q2", "Q404"], "P749": "Q3", "P1813": "ACME"},
+        }});
+        fs::write(&paths.kv, format!("{}\n", entry)).unwrap();
+        let feature = json!({"type": "Feature", "id": "Q1", "properties": {
+            "type": "organization", "label": "Acme", "P17": "Q55",
+        }});
+        fs::write(paths.geojson.as_ref().unwrap(), format!("{}\n", feature)).unwrap();
+        write_referenced_ids(&paths, &config).unwrap();
+        assert_eq!(
+            fs::read_to_string(referenced_ids_path(&config)).unwrap(),
+            "Q2\nQ3\nQ55\nQ404\n"
+        );
+        Config::for_test(&dir)
+    }
+
+    /// Resolve with the options of the extraction, and return the IDs that were requested
+    fn resolve_with_labels(config: &Config) -> Vec<Vec<String>> {
+        resolve_with(config, MemorySource::new(LABELS))
+    }
+
+    /// Resolve with the options of the extraction and the labels of a source, and return the
+    /// IDs that were requested
+    fn resolve_with(config: &Config, source: MemorySource) -> Vec<Vec<String>> {
+        let requested = source.requested.clone();
+        let settings = DeferredSettings::load(config).unwrap();
+        resolve(&settings.apply(config), settings, Box::new(source)).unwrap();
+        let requested = requested.lock().unwrap().clone();
+        requested
+    }
+
+    fn read_json(path: &str) -> Value {
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn resolves_the_output_of_the_extraction_once() {
+        let config = extract_deferred("resolve");
+        let kv_path = format!("{}/entity_kv_store.jsonl", config.output_dir);
+        let collection_path = format!("{}/entities.geojson", config.output_dir);
+
+        assert_eq!(resolve_with_labels(&config), [["Q2", "Q3", "Q55", "Q404"]]);
+        let kv = read_json(&kv_path);
+        let collection = read_json(&collection_path);
+        assert_eq!(
+            kv,
+            json!({"Q1": {
+                "label": "Acme",
+                "props": {"P17": "Netherlands", "P112": ["Ada Example", "Q404"], "P1813": "ACME"},
+            }})
+        );
+        assert_eq!(
+            collection["features"][0]["properties"],
+            json!({"type": "organization", "label": "Acme", "P17": "Netherlands"})
+        );
+
+        // Only the unknown ID is requested again, and the output is left as it is
+        assert_eq!(resolve_with_labels(&config), [["Q404"]]);
+        assert_eq!(read_json(&kv_path), kv);
+        assert_eq!(read_json(&collection_path), collection);
+        fs::remove_dir_all(&config.output_dir).unwrap();
+    }
+
+    #[test]
+    fn writes_the_labels_to_a_label_table() {
+        let mut config = extract_deferred("label_table");
+        config.label_table = true;
+        let kv_path = format!("{}/entity_kv_store.jsonl", config.output_dir);
+        let kv = read_json(&kv_path);
+
+        resolve_with_labels(&config);
+        let labels = fs::read_to_string(format!("{}/labels.csv", config.output_dir)).unwrap();
+        let resolved_kv = read_json(&kv_path);
+        fs::remove_dir_all(&config.output_dir).unwrap();
+
+        assert_eq!(labels, "Q2,Ada Example,en\nQ3,,en\nQ55,Netherlands,en\n");
+        assert_eq!(resolved_kv, kv);
+    }
+
+    #[test]
+    fn resolves_the_ids_that_failed_in_the_next_run() {
+        let config = extract_deferred("retry");
+        let kv_path = format!("{}/entity_kv_store.jsonl", config.output_dir);
+        let features_path = format!("{}/entities.geojsonl", config.output_dir);
+        let collection_path = format!("{}/entities.geojson", config.output_dir);
+        fs::write(
+            format!("{}/entity_cache.csv", config.output_dir),
+            "Q55,Netherlands,en\nQ3,,en\n",
+        )
+        .unwrap();
+
+        // The request for the IDs that are not cached fails
+        let failing = MemorySource::new(LABELS).failing(&["Q2"]);
+        assert_eq!(resolve_with(&config, failing), [["Q2", "Q404"]]);
+        assert_eq!(
+            read_json(&kv_path)["Q1"]["props"],
+            json!({"P17": "Netherlands", "P112": ["Q2", "Q404"], "P1813": "ACME"})
+        );
+        assert!(!PathBuf::from(&collection_path).exists());
+        assert_eq!(
+            read_json(&features_path)["properties"]["P17"],
+            json!("Netherlands")
+        );
+
+        assert_eq!(resolve_with_labels(&config), [["Q2", "Q404"]]);
+        let kv = read_json(&kv_path);
+        assert_eq!(
+            kv["Q1"]["props"],
+            json!({"P17": "Netherlands", "P112": ["Ada Example", "Q404"], "P1813": "ACME"})
+        );
+        let collection = read_json(&collection_path);
+        assert_eq!(
+            collection["features"][0]["properties"]["P17"],
+            json!("Netherlands")
+        );
+
+        // Unknown IDs are not a failure, so the output is resolved now
+        assert_eq!(resolve_with_labels(&config), [["Q404"]]);
+        assert_eq!(read_json(&kv_path), kv);
+        assert_eq!(read_json(&collection_path), collection);
+        fs::remove_dir_all(&config.output_dir).unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use crate::processing_error::ProcessingError;
+
+/// Built-in entity type definitions, used when no definitions file is given
+const DEFAULT_DEFINITIONS: &str = include_str!("../data/entity_types.toml");
+
+/// Definition of an entity type: which entities match it, and which properties to extract
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EntityTypeDefinition {
+    /// P31 (instance of) classes of the entity type
+    pub classes: Vec<String>,
+    /// Classes whose subclasses are not matched
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Entity type whose properties are extracted too, before the own properties
+    #[serde(default)]
+    pub extends: Option<String>,
+    /// Properties to extract
+    #[serde(default)]
+    pub properties: Vec<PropertyDefinition>,
+}
+
+/// A property to extract, and how to export it
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "PropertyEntry")]
+pub struct PropertyDefinition {
+    /// PID of the property
+    pub id: String,
+    /// Key of the value in the output
+    pub key: String,
+    /// Export all values of the best rank as an array, instead of the first one
+    pub all: bool,
+    /// Maximum number of values in the array
+    pub max: Option<usize>,
+    /// Record the rank of the exported value(s)
+    pub rank: bool,
+    /// Qualifiers to export with each value, turning it into an object like `{value, start, end}`
+    pub qualifiers: Vec<String>,
+    /// Export the value followed by its own value of the same property, and so on, as an array
+    pub chain: bool,
+}
+
+/// A property is either given by its PID, or by a table with the PID and options
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PropertyEntry {
+    Id(String),
+    Table(PropertyTable),
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PropertyTable {
+    id: String,
+    /// Key of the value in the output, by default the PID
+    key: Option<String>,
+    #[serde(default)]
+    all: bool,
+    /// Maximum number of values, which implies `all`
+    max: Option<usize>,
+    #[serde(default)]
+    rank: bool,
+    #[serde(default)]
+    qualifiers: Vec<String>,
+    #[serde(default)]
+    chain: bool,
+}
+
+impl From<PropertyEntry> for PropertyDefinition {
+    fn from(entry: PropertyEntry) -> Self {
+        let table = match entry {
+            PropertyEntry::Id(id) => PropertyTable {
+                id,
+                ..Default::default()
+            },
+            PropertyEntry::Table(table) => table,
+        };
+        Self {
+            key: table.key.unwrap_or_else(|| table.id.clone()),
+            id: table.id,
+            all: table.all || table.max.is_some(),
+            max: table.max,
+            rank: table.rank,
+            qualifiers: table.qualifiers,
+            chain: table.chain,
+        }
+    }
+}
+
+/// All entity type definitions, in the order of the definitions file
+#[derive(Debug, Clone)]
+pub struct Definitions {
+    entity_types: Vec<(String, EntityTypeDefinition)>,
+}
+
+impl Definitions {
+    /// Load the definitions from a TOML file, or the built-in definitions
+    pub fn load(path: Option<&Path>) -> Result<Self, ProcessingError> {
+        match path {
+            Some(path) => Self::parse(&fs::read_to_string(path)?, &path.display().to_string()),
+            None => Self::parse(DEFAULT_DEFINITIONS, "the built-in definitions"),
+        }
+    }
+
+    fn parse(definitions: &str, source: &str) -> Result<Self, ProcessingError> {
+        let invalid = |e: toml::de::Error| {
+            ProcessingError::ConfigError(format!("invalid definitions in {}: {}", source, e))
+        };
+        // The table keeps the order of the file, which determines the order of the outputs
+        let table: toml::Table = toml::from_str(definitions).map_err(invalid)?;
+        let mut entity_types: Vec<(String, EntityTypeDefinition)> = Vec::new();
+        for (name, definition) in table {
+            let mut definition: EntityTypeDefinition = definition.try_into().map_err(|e| {
+                ProcessingError::ConfigError(format!(
+                    "invalid definition of entity type '{}' in {}: {}",
+                    name, source, e
+                ))
+            })?;
+            // The extended type is defined before, so its own base is already resolved
+            if let Some(base) = &definition.extends {
+                let (_, base) = entity_types
+                    .iter()
+                    .find(|(entity_type, _)| entity_type == base)
+                    .ok_or_else(|| {
+                        ProcessingError::ConfigError(format!(
+                            "entity type '{}' extends '{}', which is not defined before it in {}",
+                            name, base, source
+                        ))
+                    })?;
+                definition.properties = base
+                    .properties
+                    .iter()
+                    .cloned()
+                    .chain(definition.properties)
+                    .collect();
+            }
+            // A value would silently overwrite the value of another property with its key
+            let mut keys = HashSet::new();
+            if let Some(property) = definition
+                .properties
+                .iter()
+                .find(|property| !keys.insert(property.key.as_str()))
+            {
+                return Err(ProcessingError::ConfigError(format!(
+                    "the key '{}' of {} is used twice in entity type '{}' in {}",
+                    property.key, property.id, name, source
+                )));
+            }
+            entity_types.push((name, definition));
+        }
+        Ok(Self { entity_types })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&EntityTypeDefinition> {
+        self.entity_types
+            .iter()
+            .find(|(entity_type, _)| entity_type == name)
+            .map(|(_, definition)| definition)
+    }
+
+    /// Names of all defined entity types
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entity_types.iter().map(|(name, _)| name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_built_in_definitions() {
+        let definitions = Definitions::load(None).unwrap();
+        assert!(definitions.get("person").is_some());
+    }
+
+    #[test]
+    fn extends_the_properties_of_another_type() {
+        let definitions = r#"
+            [organization]
+            classes = ["Q43229"]
+            properties = ["P17", { id = "P154", key = "logo" }]
+
+            [research_institute]
+            classes = ["Q31855"]
+            extends = "organization"
+            properties = ["P101"]
+
+            [university]
+            classes = ["Q3918"]
+            extends = "research_institute"
+        "#;
+        let definitions = Definitions::parse(definitions, "test").unwrap();
+        let keys = |name| -> Vec<String> {
+            let definition = definitions.get(name).unwrap();
+            definition
+                .properties
+                .iter()
+                .map(|p| p.key.clone())
+                .collect()
+        };
+        assert_eq!(keys("organization"), ["P17", "logo"]);
+        assert_eq!(keys("research_institute"), ["P17", "logo", "P101"]);
+        assert_eq!(keys("university"), ["P17", "logo", "P101"]);
+        assert_eq!(definitions.get("university").unwrap().classes, ["Q3918"]);
+    }
+
+    #[test]
+    fn rejects_extending_an_undefined_type() {
+        let definitions = r#"
+            [research_institute]
+            classes = ["Q31855"]
+            extends = "organization"
+
+            [organization]
+            classes = ["Q43229"]
+        "#;
+        assert!(Definitions::parse(definitions, "test").is_err());
+    }
+
+    #[test]
+    fn rejects_keys_that_are_used_twice() {
+        let definitions = r#"
+            [organization]
+            classes = ["Q43229"]
+            properties = [{ id = "P18", key = "image" }, { id = "P154", key = "image" }]
+        "#;
+        assert!(Definitions::parse(definitions, "test").is_err());
+
+        // Also when the key is used by the extended type
+        let definitions = r#"
+            [organization]
+            classes = ["Q43229"]
+            properties = [{ id = "P154", key = "logo" }]
+
+            [company]
+            classes = ["Q783794"]
+            extends = "organization"
+            properties = [{ id = "P18", key = "logo" }]
+        "#;
+        assert!(Definitions::parse(definitions, "test").is_err());
+    }
+}
@@ -0,0 +1,326 @@
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use memchr::memchr;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+
+use crate::processing_error::ProcessingError;
+
+/// Size of the blocks handed over by the decompression thread
+const READ_AHEAD_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+/// Number of decompressed blocks that may be waiting to be parsed
+const READ_AHEAD_BLOCKS: usize = 16;
+/// Number of lines that are read from a stream before they are processed in parallel
+pub const STREAM_BATCH_SIZE: usize = 50000;
+
+/// Compression format of a Wikidata dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Bzip2,
+}
+
+/// Detect the compression format, first by magic bytes, then by file extension
+pub fn detect_compression(path: &Path) -> Result<Compression, ProcessingError> {
+    let mut magic = [0u8; 3];
+    let mut file = File::open(path)?;
+    let read = file.read(&mut magic)?;
+
+    if read >= 2 && magic[..2] == [0x1f, 0x8b] {
+        return Ok(Compression::Gzip);
+    }
+    if read == 3 && &magic == b"BZh" {
+        return Ok(Compression::Bzip2);
+    }
+
+    let compression = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => Compression::Gzip,
+        Some("bz2") => Compression::Bzip2,
+        _ => Compression::None,
+    };
+    Ok(compression)
+}
+
+/// Reader that keeps track of the number of bytes read from the underlying file
+struct CountingReader<R> {
+    inner: R,
+    bytes_read: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes_read.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+/// Reader that decompresses on a separate thread, so decompression and parsing overlap
+struct ReadAheadReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl ReadAheadReader {
+    fn new<R: Read + Send + 'static>(mut decoder: R) -> Self {
+        let (sender, receiver) = sync_channel(READ_AHEAD_BLOCKS);
+        thread::spawn(move || loop {
+            let mut block = vec![0u8; READ_AHEAD_BLOCK_SIZE];
+            let mut filled = 0;
+            // Fill the whole block, as decoders tend to return small reads
+            let result = loop {
+                match decoder.read(&mut block[filled..]) {
+                    Ok(0) => break Ok(()),
+                    Ok(n) => {
+                        filled += n;
+                        if filled == block.len() {
+                            break Ok(());
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => break Err(e),
+                }
+            };
+            let done = filled < block.len();
+            block.truncate(filled);
+            let message = match result {
+                Ok(()) => Ok(block),
+                Err(e) => Err(e),
+            };
+            let failed = message.is_err();
+            // Stop when the reader is dropped, at the end of the stream, or on an error
+            if sender.send(message).is_err() || done || failed {
+                break;
+            }
+        });
+
+        Self {
+            receiver,
+            block: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ReadAheadReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for ReadAheadReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos >= self.block.len() {
+            match self.receiver.recv() {
+                Ok(Ok(block)) => {
+                    if block.is_empty() {
+                        break;
+                    }
+                    self.block = block;
+                    self.pos = 0;
+                }
+                Ok(Err(e)) => return Err(e),
+                // The decompression thread has finished
+                Err(_) => {
+                    self.block.clear();
+                    self.pos = 0;
+                    break;
+                }
+            }
+        }
+        Ok(&self.block[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.block.len());
+    }
+}
+
+/// An opened (and possibly decompressed) Wikidata dump
+pub struct DumpReader {
+    /// Line reader over the decompressed JSON
+    pub reader: Box<dyn BufRead + Send>,
+    /// Size of the file on disk, i.e. the compressed size
+    pub file_size: u64,
+    /// Number of bytes read from the file on disk, used for progress reporting
+    pub bytes_read: Arc<AtomicU64>,
+    pub compression: Compression,
+}
+
+/// Open the dump, transparently stream-decompressing gzip and bzip2 input
+pub fn open_dump(path: &Path) -> Result<DumpReader, ProcessingError> {
+    let compression = detect_compression(path)?;
+    let file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+    let bytes_read = Arc::new(AtomicU64::new(0));
+    let counting = BufReader::with_capacity(
+        1024 * 1024,
+        CountingReader {
+            inner: file,
+            bytes_read: Arc::clone(&bytes_read),
+        },
+    );
+
+    // Multi-member decoders, as parallel compressors (pigz, pbzip2) write one member per block
+    let reader: Box<dyn BufRead + Send> = match compression {
+        Compression::None => Box::new(counting),
+        Compression::Gzip => Box::new(ReadAheadReader::new(MultiGzDecoder::new(counting))),
+        Compression::Bzip2 => Box::new(ReadAheadReader::new(MultiBzDecoder::new(counting))),
+    };
+
+    Ok(DumpReader {
+        reader,
+        file_size,
+        bytes_read,
+        compression,
+    })
+}
+
+/// Read the next line of a stream, including the newline. A partially downloaded
+/// compressed dump ends in the middle of a compressed block, which is reported as such.
+pub fn read_line(reader: &mut dyn BufRead, line: &mut Vec<u8>) -> Result<usize, ProcessingError> {
+    reader.read_until(b'\n', line).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            ProcessingError::IncompleteDump(format!(
+                "the compressed stream ended unexpectedly ({})",
+                e
+            ))
+        } else {
+            ProcessingError::IoError(e)
+        }
+    })
+}
+
+/// Memory-map an uncompressed dump, so workers can each parse their own byte range
+pub fn map_dump(path: &Path) -> Result<Mmap, ProcessingError> {
+    let file = File::open(path)?;
+    // SAFETY: the dump is only read, and must not be modified while it is being processed
+    let mmap = unsafe { Mmap::map(&file)? };
+    Ok(mmap)
+}
+
+/// Split the data into at most `count` byte ranges that start and end at line boundaries
+pub fn split_ranges(data: &[u8], count: usize) -> Vec<Range<usize>> {
+    let chunk_size = data.len().div_ceil(count.max(1)).max(1);
+    let mut ranges = Vec::with_capacity(count);
+    let mut start = 0;
+    while start < data.len() {
+        let mut end = (start + chunk_size).min(data.len());
+        // Move the end to just after the next newline, unless the chunk already ends on one
+        if end < data.len() {
+            end = match memchr(b'\n', &data[end - 1..]) {
+                Some(pos) => end + pos,
+                None => data.len(),
+            };
+        }
+        ranges.push(start..end);
+        start = end;
+    }
+    ranges
+}
+
+/// Iterator over the lines in a byte range, yielding the absolute offset of each line
+pub struct Lines<'a> {
+    data: &'a [u8],
+    range: Range<usize>,
+}
+
+impl<'a> Lines<'a> {
+    pub fn new(data: &'a [u8], range: Range<usize>) -> Self {
+        Self { data, range }
+    }
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = (usize, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.range.start >= self.range.end {
+            return None;
+        }
+        let offset = self.range.start;
+        let remaining = &self.data[offset..self.range.end];
+        let (line, next) = match memchr(b'\n', remaining) {
+            Some(pos) => (&remaining[..pos], offset + pos + 1),
+            None => (remaining, self.range.end),
+        };
+        self.range.start = next;
+        Some((offset, line.strip_suffix(b"\r").unwrap_or(line)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The lines of all ranges, in order
+    fn lines(data: &[u8], count: usize) -> Vec<(usize, &[u8])> {
+        split_ranges(data, count)
+            .into_iter()
+            .flat_map(|range| Lines::new(data, range))
+            .collect()
+    }
+
+    #[test]
+    fn splits_empty_input_into_no_ranges() {
+        assert!(split_ranges(b"", 4).is_empty());
+        assert!(lines(b"", 4).is_empty());
+    }
+
+    #[test]
+    fn splits_at_line_boundaries() {
+        let data = b"aaaa\nbb\ncccccc\nd\n";
+        let ranges = split_ranges(data, 3);
+        for range in &ranges {
+            assert!(range.end == data.len() || data[range.end - 1] == b'\n');
+        }
+        assert_eq!(ranges.last().unwrap().end, data.len());
+        let expected: Vec<(usize, &[u8])> =
+            vec![(0, b"aaaa"), (5, b"bb"), (8, b"cccccc"), (15, b"d")];
+        assert_eq!(lines(data, 3), expected);
+        assert_eq!(lines(data, 1), expected);
+    }
+
+    #[test]
+    fn keeps_chunks_that_end_on_a_newline() {
+        // Chunks of 3 bytes end exactly after each newline
+        let data = b"ab\ncd\nef\n";
+        assert_eq!(split_ranges(data, 3), vec![0..3, 3..6, 6..9]);
+    }
+
+    #[test]
+    fn reads_the_last_line_without_a_newline() {
+        let data = b"ab\ncd";
+        let expected: Vec<(usize, &[u8])> = vec![(0, b"ab"), (3, b"cd")];
+        assert_eq!(lines(data, 2), expected);
+        assert_eq!(lines(data, 1), expected);
+    }
+
+    #[test]
+    fn strips_carriage_returns() {
+        let data = b"ab\r\ncd\r\n";
+        let expected: Vec<(usize, &[u8])> = vec![(0, b"ab"), (4, b"cd")];
+        assert_eq!(lines(data, 2), expected);
+    }
+
+    #[test]
+    fn splits_into_fewer_ranges_than_requested() {
+        let data = b"ab\ncd\n";
+        let ranges = split_ranges(data, 16);
+        assert_eq!(ranges, vec![0..3, 3..6]);
+        assert_eq!(lines(data, 16).len(), 2);
+    }
+}
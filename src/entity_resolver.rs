@@ -1,15 +1,18 @@
-use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct EntityCache {
-    entries: HashMap<String, String>,
+use crate::label_source::LabelSource;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct EntityCache {
+    /// Labels by ID and language, where an empty label means there is no label
+    entries: HashMap<String, HashMap<String, String>>,
 }
 
 impl EntityCache {
@@ -19,8 +22,12 @@ impl EntityCache {
         }
     }
 
-    // Load cache from CSV file
-    fn load_from_csv(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+    // Load cache from CSV file, with rows of ID, label and language. Rows without a language,
+    // from before multiple languages were supported, are in the primary language.
+    pub fn load_from_csv(
+        path: &Path,
+        primary_language: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         if !path.exists() {
             return Ok(Self::new());
         }
@@ -29,190 +36,240 @@ impl EntityCache {
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
 
-        let mut entries = HashMap::new();
-        let mut rdr = csv::Reader::from_reader(contents.as_bytes());
+        let mut entries: HashMap<String, HashMap<String, String>> = HashMap::new();
+        // The cache is saved without a header row
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(contents.as_bytes());
         for result in rdr.records() {
             let record = result?;
-            if record.len() == 2 {
-                entries.insert(record[0].to_string(), record[1].to_string());
-            }
+            let lang = match record.len() {
+                2 => primary_language,
+                3 => &record[2],
+                _ => continue,
+            };
+            entries
+                .entry(record[0].to_string())
+                .or_default()
+                .insert(lang.to_string(), record[1].to_string());
         }
 
         Ok(Self { entries })
     }
 
-    // Save cache to CSV file
+    // Save cache to CSV file, via a temporary file so a crash never leaves a partial cache
     fn save_to_csv(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
-        let mut wtr = csv::Writer::from_path(path)?;
+        let tmp_path = path.with_extension("csv.tmp");
+        let mut wtr = csv::Writer::from_path(&tmp_path)?;
 
-        for (id, label) in &self.entries {
-            wtr.write_record(&[id, label])?;
+        for (id, labels) in &self.entries {
+            for (lang, label) in labels {
+                wtr.write_record([id, label, lang])?;
+            }
         }
 
         wtr.flush()?;
+        std::fs::rename(&tmp_path, path)?;
         Ok(())
     }
+
+    /// Labels of an ID in the languages, if they are all cached
+    pub fn get(&self, id: &str, languages: &[String]) -> Option<Vec<String>> {
+        let labels = self.entries.get(id)?;
+        languages
+            .iter()
+            .map(|lang| labels.get(lang).cloned())
+            .collect()
+    }
 }
 
+/// Resolves the IDs in the properties to their labels, from a label source. The labels are
+/// cached, and the cache is saved to disk if it has a path.
 pub struct EntityResolver {
     cache: Arc<RwLock<EntityCache>>,
-    cache_file_path: PathBuf,
-    save_counter: Arc<Mutex<usize>>,
-    /// Wikibase API url
-    api_base_url: String,
-    /// Required language
-    language: String,
-    /// Required language including English in case the required language is not English
-    languages: String,
+    cache_file_path: Option<PathBuf>,
+    /// Held while saving, so the cache is never saved by two threads at once
+    save_lock: Mutex<()>,
+    source: Box<dyn LabelSource>,
+    /// Required languages, the first one being the primary language
+    languages: Vec<String>,
+    /// Number of IDs that could not be resolved because the source failed
+    unresolved: AtomicU64,
 }
 
 impl EntityResolver {
-    pub fn new(cache_file_path: PathBuf, api_base_url: String, language: &str) -> Self {
+    pub fn new(
+        cache_file_path: Option<PathBuf>,
+        source: Box<dyn LabelSource>,
+        languages: &[String],
+    ) -> Self {
         // Try to load existing cache, or create a new one
-        let cache = match EntityCache::load_from_csv(&cache_file_path) {
-            Ok(loaded_cache) => Arc::new(RwLock::new(loaded_cache)),
-            Err(_) => Arc::new(RwLock::new(EntityCache::new())),
-        };
-
-        let languages = if language == "en" {
-            "en".to_string()
-        } else {
-            format!("{},en", language)
-        };
+        let cache = cache_file_path
+            .as_deref()
+            .and_then(|path| EntityCache::load_from_csv(path, &languages[0]).ok())
+            .unwrap_or_default();
 
         Self {
-            cache,
+            cache: Arc::new(RwLock::new(cache)),
             cache_file_path,
-            save_counter: Arc::new(Mutex::new(0)),
-            api_base_url,
-            language: language.to_string(),
-            languages,
+            save_lock: Mutex::new(()),
+            source,
+            languages: languages.to_vec(),
+            unresolved: AtomicU64::new(0),
         }
     }
 
-    // Check if ID exists in cache without locking
-    fn get_cached_label(&self, id: &str) -> Option<String> {
-        self.cache.read().unwrap().entries.get(id).cloned()
+    /// Save the cache to disk, when making a checkpoint and at the end of a run. The whole cache
+    /// is written each time, so it is not saved after every update.
+    pub fn save_cache(&self) {
+        let Some(cache_file_path) = &self.cache_file_path else {
+            return;
+        };
+        let _saving = self.save_lock.lock().unwrap();
+        let cache = self.cache.read().unwrap();
+        if let Err(e) = cache.save_to_csv(cache_file_path) {
+            eprintln!("Failed to save cache: {}", e);
+        }
     }
 
-    // Resolve entities, with optimized locking
-    pub fn resolve_entity_ids(&self, mut properties: Map<String, Value>) -> Map<String, Value> {
-        // Collect IDs to resolve
-        let mut ids_to_resolve = HashSet::new();
+    /// Number of IDs that could not be resolved because the source failed
+    pub fn unresolved(&self) -> u64 {
+        self.unresolved.load(Ordering::Relaxed)
+    }
 
-        for (_, value) in &properties {
-            if let Some(full_id) = value.as_str() {
-                // Extract base ID
-                let base_id = full_id.split('$').next().unwrap_or(full_id);
-
-                // Check if ID needs resolving
-                if base_id.starts_with('Q')
-                    && base_id.len() > 1
-                    && base_id.chars().skip(1).all(|c| c.is_ascii_digit())
-                {
-                    // Only add if not in cache
-                    if self.get_cached_label(base_id).is_none() {
-                        ids_to_resolve.insert(base_id.to_string());
-                    }
-                }
-            }
+    /// Labels of an ID in the required languages, if they are all cached
+    pub fn get_cached_labels(&self, id: &str) -> Option<Vec<String>> {
+        self.cache.read().unwrap().get(id, &self.languages)
+    }
+
+    /// Get the labels of the IDs that are not cached yet from the source, in a single request.
+    /// The IDs that the source fails to look up are counted as unresolved.
+    pub fn prefetch(&self, ids: &[&str]) {
+        let ids: Vec<&str> = ids
+            .iter()
+            .copied()
+            .filter(|id| self.get_cached_labels(id).is_none())
+            .collect();
+        if ids.is_empty() {
+            return;
+        }
+        let labels = self.source.labels(&ids);
+        // The failures are counted rather than logged, as they come in bursts when the source
+        // is unavailable
+        if let Some(failure) = labels.failure {
+            self.unresolved
+                .fetch_add(failure.ids.len() as u64, Ordering::Relaxed);
+        }
+        if !labels.labels.is_empty() {
+            self.batch_update_cache(labels.labels);
         }
+    }
 
-        // Resolve unknown entities via API
-        if !ids_to_resolve.is_empty() {
-            self.fetch_and_cache_entities(&ids_to_resolve, &self.api_base_url);
+    // Resolve entities, with optimized locking. Values are either IDs, or arrays of IDs.
+    pub fn resolve_entity_ids(&self, properties: Map<String, Value>) -> Map<String, Value> {
+        // Collect IDs to resolve
+        let mut ids_to_resolve = HashSet::new();
+        for value in properties.values() {
+            let mut ids = Vec::new();
+            entity_ids(value, &mut ids);
+            ids_to_resolve.extend(ids);
         }
 
-        let mut keys_to_remove = Vec::new();
-        // Replace IDs with labels
-        for (key, value) in properties.iter_mut() {
-            if let Some(full_id) = value.as_str() {
-                let base_id = full_id.split('$').next().unwrap_or(full_id);
+        // Get the labels of unknown entities from the source. When it fails, the IDs are kept
+        // as they are, and are tried again when they occur again.
+        let ids: Vec<&str> = ids_to_resolve.into_iter().collect();
+        self.prefetch(&ids);
+        self.resolve_cached(properties)
+    }
 
-                if let Some(label) = self.get_cached_label(base_id) {
-                    if !label.is_empty() {
-                        *value = Value::String(label);
-                    } else {
-                        // Remove the property if the label is empty
-                        keys_to_remove.push(key.clone());
-                    }
-                }
-            }
-        }
+    /// Replace the IDs in the properties with their cached labels, without asking the source,
+    /// and remove the values of which the label is empty
+    pub fn resolve_cached(&self, mut properties: Map<String, Value>) -> Map<String, Value> {
+        let cached_labels = |id: &str| self.get_cached_labels(id);
+        properties.retain(|_, value| replace_with_label(value, &cached_labels, &self.languages));
+        properties
+    }
 
-        // Remove keys after the loop
-        for key in keys_to_remove {
-            properties.remove(&key);
+    // Batch update cache with a write lock
+    fn batch_update_cache(&self, labels: HashMap<String, Vec<String>>) {
+        let mut cache = self.cache.write().unwrap();
+        for (id, labels) in labels {
+            cache
+                .entries
+                .entry(id)
+                .or_default()
+                .extend(self.languages.iter().cloned().zip(labels));
         }
-        properties
     }
+}
 
-    // Fetch and cache entities
-    fn fetch_and_cache_entities(&self, ids: &HashSet<String>, api_base_url: &str) {
-        let client = Client::new();
-        let batch_size = 50;
-
-        // Convert to vec for batching
-        let ids_vec: Vec<String> = ids.iter().cloned().collect();
-
-        for batch in ids_vec.chunks(batch_size) {
-            // Construct API request
-            let ids_param = batch.join("|");
-            let response = client
-                .get(api_base_url)
-                .query(&[
-                    ("action", "wbgetentities"),
-                    ("format", "json"),
-                    ("ids", &ids_param),
-                    ("props", "labels"),
-                    ("languages", &self.languages),
-                ])
-                .send()
-                .expect("Failed to send request");
-
-            let json: Value = response.json().expect("Failed to parse JSON");
-
-            // Extract and cache labels
-            if let Some(entities) = json["entities"].as_object() {
-                let mut labels_to_cache = HashMap::new();
-
-                for (id, entity) in entities {
-                    if let Some(label) = entity["labels"][&self.language]["value"]
-                        .as_str()
-                        .or(entity["labels"]["en"]["value"].as_str().or(Some("")))
-                    {
-                        labels_to_cache.insert(id.clone(), label.to_string());
-                    }
-                }
+/// The value that replaces an ID: its label, or a map of its labels by language when there
+/// are multiple languages. Returns `None` when there is no label at all.
+fn label_value(labels: Vec<String>, languages: &[String]) -> Option<Value> {
+    if languages.len() == 1 {
+        let label = labels.into_iter().next()?;
+        return (!label.is_empty()).then_some(Value::String(label));
+    }
+    let labels: Map<String, Value> = languages
+        .iter()
+        .zip(labels)
+        .filter(|(_, label)| !label.is_empty())
+        .map(|(lang, label)| (lang.clone(), Value::String(label)))
+        .collect();
+    (!labels.is_empty()).then_some(Value::Object(labels))
+}
 
-                // Batch write to cache
-                if !labels_to_cache.is_empty() {
-                    self.batch_update_cache(labels_to_cache);
+/// Replace the IDs in a value with their labels in the languages, also inside arrays and
+/// objects (values with qualifiers). IDs of which the labels are unknown are kept as they are.
+/// Returns false if the value should be removed: the label is empty, all values of an array
+/// are removed, or the main value of an object is removed.
+pub fn replace_with_label(
+    value: &mut Value,
+    labels: &dyn Fn(&str) -> Option<Vec<String>>,
+    languages: &[String],
+) -> bool {
+    match value {
+        Value::String(full_id) => {
+            let base_id = full_id.split('$').next().unwrap_or(full_id);
+
+            if let Some(labels) = labels(base_id) {
+                match label_value(labels, languages) {
+                    Some(label) => *value = label,
+                    None => return false,
                 }
             }
+            true
         }
-    }
-
-    // Batch update cache with a write lock
-    fn batch_update_cache(&self, labels: HashMap<String, String>) {
-        let mut save_count = self.save_counter.lock().unwrap();
-
-        // Update cache with write lock
-        {
-            let mut cache = self.cache.write().unwrap();
-            for (id, label) in labels {
-                cache.entries.insert(id, label);
-            }
+        Value::Array(values) => {
+            values.retain_mut(|value| replace_with_label(value, labels, languages));
+            !values.is_empty()
+        }
+        Value::Object(object) => {
+            let had_value = object.contains_key("value");
+            object.retain(|_, value| replace_with_label(value, labels, languages));
+            !had_value || object.contains_key("value")
         }
+        _ => true,
+    }
+}
 
-        // Periodically save to disk (e.g., every 100 updates)
-        *save_count += 1;
-        if *save_count % 100 == 0 {
-            let cache = self.cache.read().unwrap();
-            if let Err(e) = cache.save_to_csv(&self.cache_file_path) {
-                eprintln!("Failed to save cache: {}", e);
+/// The base IDs of the entities (e.g. Q42) in a value or an array of values
+pub fn entity_ids<'a>(value: &'a Value, ids: &mut Vec<&'a str>) {
+    match value {
+        Value::String(full_id) => {
+            // Extract base ID
+            let base_id = full_id.split('$').next().unwrap_or(full_id);
+            if base_id.starts_with('Q')
+                && base_id.len() > 1
+                && base_id.chars().skip(1).all(|c| c.is_ascii_digit())
+            {
+                ids.push(base_id);
             }
         }
+        Value::Array(values) => values.iter().for_each(|value| entity_ids(value, ids)),
+        Value::Object(object) => object.values().for_each(|value| entity_ids(value, ids)),
+        _ => {}
     }
 }
 
@@ -234,3 +291,97 @@ impl EntityResolver {
 //     let resolved_properties =
 //         resolver.resolve_entity_ids(properties, "https://www.wikidata.org/w/api.php");
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::label_source::MemorySource;
+    use serde_json::json;
+
+    fn resolver(source: MemorySource, languages: &[&str]) -> EntityResolver {
+        let languages: Vec<String> = languages.iter().map(|lang| lang.to_string()).collect();
+        EntityResolver::new(None, Box::new(source), &languages)
+    }
+
+    fn properties(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn replaces_ids_with_labels() {
+        let source = MemorySource::new(&[("Q5", &["human"]), ("Q55", &["Netherlands"])]);
+        let resolver = resolver(source, &["en"]);
+        let resolved = resolver.resolve_entity_ids(properties(json!({
+            "P31": "Q5",
+            "P27": ["Q55", "Q404"],
+            "P39": {"value": "Q55", "start": {"date": "2010", "precision": "year"}},
+            "P1082": {"value": {"amount": 905234}},
+        })));
+        assert_eq!(
+            Value::Object(resolved),
+            json!({
+                "P31": "human",
+                // Unknown IDs are kept as they are
+                "P27": ["Netherlands", "Q404"],
+                "P39": {"value": "Netherlands", "start": {"date": "2010", "precision": "year"}},
+                "P1082": {"value": {"amount": 905234}},
+            })
+        );
+    }
+
+    #[test]
+    fn removes_values_without_label() {
+        let source = MemorySource::new(&[("Q1", &[""]), ("Q2", &["two"])]);
+        let resolver = resolver(source, &["en"]);
+        let resolved = resolver.resolve_entity_ids(properties(json!({
+            "P1": "Q1",
+            "P2": ["Q1", "Q2"],
+            "P3": ["Q1"],
+            "P4": {"value": "Q1", "of": "Q2"},
+            "P5": {"value": "Q2", "of": "Q1"},
+        })));
+        assert_eq!(
+            Value::Object(resolved),
+            json!({"P2": ["two"], "P5": {"value": "two"}})
+        );
+    }
+
+    #[test]
+    fn resolves_to_maps_by_language() {
+        let source =
+            MemorySource::new(&[("Q55", &["Netherlands", "Nederland"]), ("Q1", &["one", ""])]);
+        let resolver = resolver(source, &["en", "nl"]);
+        let resolved = resolver.resolve_entity_ids(properties(json!({"P17": "Q55", "P1": "Q1"})));
+        assert_eq!(
+            Value::Object(resolved),
+            json!({"P17": {"en": "Netherlands", "nl": "Nederland"}, "P1": {"en": "one"}})
+        );
+    }
+
+    #[test]
+    fn asks_the_source_once_per_id() {
+        let source = MemorySource::new(&[("Q5", &["human"])]);
+        let requested = source.requested.clone();
+        let resolver = resolver(source, &["en"]);
+        for _ in 0..3 {
+            resolver.resolve_entity_ids(properties(json!({"P31": "Q5", "P279": "Q5$abc"})));
+        }
+        assert_eq!(*requested.lock().unwrap(), vec![vec!["Q5"]]);
+    }
+
+    #[test]
+    fn resolves_prefetched_ids_from_the_cache() {
+        let source = MemorySource::new(&[("Q5", &["human"]), ("Q55", &["Netherlands"])]);
+        let requested = source.requested.clone();
+        let resolver = resolver(source, &["en"]);
+        resolver.prefetch(&["Q5", "Q55"]);
+        // Cached IDs are not requested again
+        resolver.prefetch(&["Q5"]);
+        let resolved = resolver.resolve_cached(properties(json!({"P31": "Q5", "P27": "Q404"})));
+        assert_eq!(
+            Value::Object(resolved),
+            json!({"P31": "human", "P27": "Q404"})
+        );
+        assert_eq!(*requested.lock().unwrap(), vec![vec!["Q5", "Q55"]]);
+    }
+}
@@ -0,0 +1,253 @@
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::class_hierarchy::qid_number;
+use crate::definitions::PropertyDefinition;
+use crate::processing_error::ProcessingError;
+use crate::wikibase::{best_rank_statements, parse_statements, DataValue};
+
+/// Properties with a location, of which the coordinates are used for entities without
+/// coordinates of their own: headquarters location (P159) and location (P276)
+const LOCATION_PROPERTIES: [&str; 2] = ["P159", "P276"];
+
+/// Coordinates (P625) of all entities in the dump, as (latitude, longitude) by numeric QID, to
+/// locate entities by the item of their location
+#[derive(Default, Serialize, Deserialize)]
+pub struct CoordinateIndex {
+    coordinates: HashMap<u64, (f64, f64)>,
+}
+
+/// The coordinates of the best rank of an entity, from its claims
+pub fn entity_coordinates(claims: &HashMap<&str, &RawValue>) -> Option<(f64, f64)> {
+    let statements = parse_statements(claims.get("P625")?).0;
+    best_rank_statements(&statements)
+        .into_iter()
+        .find_map(|statement| match statement.mainsnak.datavalue()? {
+            DataValue::GlobeCoordinate(coordinate) => {
+                Some((coordinate.latitude, coordinate.longitude))
+            }
+            _ => None,
+        })
+}
+
+/// Whether any of the properties is a location of which the coordinates are looked up
+pub fn has_location_properties(properties: &[PropertyDefinition]) -> bool {
+    properties
+        .iter()
+        .any(|property| LOCATION_PROPERTIES.contains(&property.id.as_str()))
+}
+
+impl FromIterator<(u64, (f64, f64))> for CoordinateIndex {
+    fn from_iter<I: IntoIterator<Item = (u64, (f64, f64))>>(coordinates: I) -> Self {
+        Self {
+            coordinates: coordinates.into_iter().collect(),
+        }
+    }
+}
+
+impl CoordinateIndex {
+    fn get(&self, qid: &str) -> Option<(f64, f64)> {
+        self.coordinates.get(&qid_number(qid)?).copied()
+    }
+
+    /// Coordinates of an entity: its own coordinates, or otherwise those of its location. Of a
+    /// location, the coordinates of the P625 qualifier are used, or those of the location item.
+    /// The properties are the extracted properties, before their IDs are resolved to labels.
+    pub fn locate(
+        &self,
+        claims: &HashMap<&str, &RawValue>,
+        properties: &Map<String, Value>,
+        definitions: &[PropertyDefinition],
+    ) -> Option<(f64, f64)> {
+        if let Some(coordinates) = entity_coordinates(claims) {
+            return Some(coordinates);
+        }
+        definitions
+            .iter()
+            .filter(|property| LOCATION_PROPERTIES.contains(&property.id.as_str()))
+            .filter_map(|property| properties.get(&property.key))
+            .flat_map(|value| match value {
+                Value::Array(values) => values.iter().collect(),
+                value => vec![value],
+            })
+            .find_map(|location| match location.get("coordinates") {
+                Some(coordinates) => Some((
+                    coordinates.get("lat")?.as_f64()?,
+                    coordinates.get("lon")?.as_f64()?,
+                )),
+                // A location with qualifiers is an object with the location as value
+                None => self.get(location.get("value").unwrap_or(location).as_str()?),
+            })
+    }
+}
+
+/// A GeoJSON point feature of an entity, with its label, type, description and properties
+pub fn feature(
+    entity_id: &str,
+    entity_type: &str,
+    (lat, lon): (f64, f64),
+    entity_data: &Map<String, Value>,
+    properties: &Map<String, Value>,
+    selected: &[String],
+) -> Value {
+    let mut feature_properties = Map::new();
+    feature_properties.insert("type".to_string(), json!(entity_type));
+    for key in ["label", "descr"] {
+        if let Some(value) = entity_data.get(key) {
+            feature_properties.insert(key.to_string(), value.clone());
+        }
+    }
+    for (key, value) in properties {
+        if selected.is_empty() || selected.contains(key) {
+            feature_properties.insert(key.clone(), value.clone());
+        }
+    }
+    json!({
+        "type": "Feature",
+        "id": entity_id,
+        "geometry": {
+            "type": "Point",
+            "coordinates": [lon, lat],
+        },
+        "properties": feature_properties,
+    })
+}
+
+/// Turn the newline-delimited features into a GeoJSON FeatureCollection, next to them.
+/// The newline-delimited file is removed afterwards.
+pub fn write_feature_collection(features_path: &Path) -> Result<(), ProcessingError> {
+    let collection_path = features_path.with_extension("geojson");
+    let mut writer = BufWriter::new(File::create(&collection_path)?);
+    writer.write_all(b"{\"type\":\"FeatureCollection\",\"features\":[\n")?;
+    for (index, line) in BufReader::new(File::open(features_path)?)
+        .lines()
+        .enumerate()
+    {
+        if index > 0 {
+            writer.write_all(b",\n")?;
+        }
+        writer.write_all(line?.as_bytes())?;
+    }
+    writer.write_all(b"\n]}\n")?;
+    writer.flush()?;
+    fs::remove_file(features_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLAIMS_WITH_COORDINATES: &str = r#"{"P625":[{"mainsnak":{"snaktype":"value","property":"P625","datavalue":{"value":{"latitude":52.0,"longitude":5.0,"globe":"http://www.wikidata.org/entity/Q2"},"type":"globecoordinate"},"datatype":"globe-coordinate"},"rank":"normal"}]}"#;
+
+    fn headquarters() -> Vec<PropertyDefinition> {
+        vec![PropertyDefinition {
+            id: "P159".to_string(),
+            key: "hq".to_string(),
+            all: false,
+            max: None,
+            rank: false,
+            qualifiers: vec!["P625".to_string()],
+            chain: false,
+        }]
+    }
+
+    fn properties(properties: Value) -> Map<String, Value> {
+        properties.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn locates_entities_by_their_own_coordinates_first() {
+        let claims: HashMap<&str, &RawValue> =
+            serde_json::from_str(CLAIMS_WITH_COORDINATES).unwrap();
+        let index = CoordinateIndex::from_iter([(9, (1.0, 2.0))]);
+        let properties = properties(json!({"hq": "Q9"}));
+        assert_eq!(
+            index.locate(&claims, &properties, &headquarters()),
+            Some((52.0, 5.0))
+        );
+    }
+
+    #[test]
+    fn locates_entities_by_the_coordinates_of_their_location() {
+        let index = CoordinateIndex::from_iter([(9, (1.0, 2.0))]);
+        let claims = HashMap::new();
+        // The coordinates of the qualifier
+        let qualified = properties(json!({
+            "hq": {"value": "Q9", "coordinates": {"lat": 3.0, "lon": 4.0}}
+        }));
+        assert_eq!(
+            index.locate(&claims, &qualified, &headquarters()),
+            Some((3.0, 4.0))
+        );
+        // The coordinates of the location item, with or without other qualifiers
+        for location in [json!("Q9"), json!({"value": "Q9"}), json!(["Q8", "Q9"])] {
+            let located = properties(json!({ "hq": location }));
+            assert_eq!(
+                index.locate(&claims, &located, &headquarters()),
+                Some((1.0, 2.0))
+            );
+        }
+        // Other properties are no location
+        let unlocated = properties(json!({"hq": "Q8", "P17": "Q9"}));
+        assert_eq!(index.locate(&claims, &unlocated, &headquarters()), None);
+    }
+
+    #[test]
+    fn writes_point_features_with_the_selected_properties() {
+        let entity_data = properties(json!({"label": "Acme", "descr": "company", "alias": ["A"]}));
+        let properties = properties(json!({"P17": "Netherlands", "P571": "1900"}));
+        let feature = feature(
+            "Q1",
+            "organization",
+            (52.0, 5.0),
+            &entity_data,
+            &properties,
+            &["P17".to_string()],
+        );
+        assert_eq!(
+            feature,
+            json!({
+                "type": "Feature",
+                "id": "Q1",
+                "geometry": {"type": "Point", "coordinates": [5.0, 52.0]},
+                "properties": {
+                    "type": "organization",
+                    "label": "Acme",
+                    "descr": "company",
+                    "P17": "Netherlands",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn writes_a_feature_collection_of_the_features() {
+        let features_path =
+            std::env::temp_dir().join(format!("features_{}.geojsonl", std::process::id()));
+        fs::write(
+            &features_path,
+            "{\"type\":\"Feature\",\"id\":\"Q1\"}\n{\"type\":\"Feature\",\"id\":\"Q2\"}\n",
+        )
+        .unwrap();
+        write_feature_collection(&features_path).unwrap();
+
+        let collection_path = features_path.with_extension("geojson");
+        let collection: Value =
+            serde_json::from_str(&fs::read_to_string(&collection_path).unwrap()).unwrap();
+        fs::remove_file(&collection_path).unwrap();
+        assert_eq!(
+            collection,
+            json!({
+                "type": "FeatureCollection",
+                "features": [{"type": "Feature", "id": "Q1"}, {"type": "Feature", "id": "Q2"}],
+            })
+        );
+        assert!(!features_path.exists());
+    }
+}
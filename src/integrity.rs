@@ -0,0 +1,230 @@
+use md5::Md5;
+use serde::de::IgnoredAny;
+use sha1::{Digest, Sha1};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+
+use crate::processing_error::ProcessingError;
+
+/// Check that the first non-blank line of the dump opens the array of entities
+pub fn check_start(first_line: Option<&[u8]>) -> Result<(), ProcessingError> {
+    match first_line {
+        Some(b"[") => Ok(()),
+        Some(line) => Err(ProcessingError::IncompleteDump(format!(
+            "expected the dump to start with '[', found {:?}",
+            String::from_utf8_lossy(&line[..line.len().min(40)])
+        ))),
+        None => Err(ProcessingError::IncompleteDump(
+            "the dump is empty".to_string(),
+        )),
+    }
+}
+
+/// Check that the last non-blank lines of the dump are a complete entity and the closing `]`
+pub fn check_end(last_lines: &[&[u8]]) -> Result<(), ProcessingError> {
+    let (entity, closing) = match last_lines {
+        [.., entity, closing] => (Some(*entity), *closing),
+        [closing] => (None, *closing),
+        [] => {
+            return Err(ProcessingError::IncompleteDump(
+                "the dump is empty".to_string(),
+            ))
+        }
+    };
+    if closing != b"]" {
+        return Err(ProcessingError::IncompleteDump(
+            "the dump does not end with ']', it was probably truncated".to_string(),
+        ));
+    }
+    // An empty array has no entities to check
+    if let Some(entity) = entity.filter(|entity| *entity != b"[") {
+        let entity = entity.strip_suffix(b",").unwrap_or(entity);
+        serde_json::from_slice::<IgnoredAny>(entity).map_err(|e| {
+            ProcessingError::IncompleteDump(format!("the last entity could not be parsed: {}", e))
+        })?;
+    }
+    Ok(())
+}
+
+/// Check the structure of an uncompressed dump up front, as both ends are available
+pub fn check_structure(data: &[u8]) -> Result<(), ProcessingError> {
+    let first_line = data
+        .split(|byte| *byte == b'\n')
+        .map(<[u8]>::trim_ascii)
+        .find(|line| !line.is_empty());
+    check_start(first_line)?;
+    let mut last_lines: Vec<&[u8]> = data
+        .rsplit(|byte| *byte == b'\n')
+        .map(<[u8]>::trim_ascii)
+        .filter(|line| !line.is_empty())
+        .take(2)
+        .collect();
+    last_lines.reverse();
+    check_end(&last_lines)
+}
+
+/// The last non-blank lines of a stream, which are only known once it has been read completely
+#[derive(Default)]
+pub struct DumpTail {
+    lines: Vec<Vec<u8>>,
+}
+
+impl DumpTail {
+    /// Remember a line, if it is one of the last two non-blank lines so far
+    pub fn push(&mut self, line: &[u8]) {
+        let line = line.trim_ascii();
+        if line.is_empty() {
+            return;
+        }
+        if self.lines.len() == 2 {
+            self.lines.remove(0);
+        }
+        self.lines.push(line.to_vec());
+    }
+
+    pub fn check(&self) -> Result<(), ProcessingError> {
+        let last_lines: Vec<&[u8]> = self.lines.iter().map(Vec::as_slice).collect();
+        check_end(&last_lines)
+    }
+}
+
+/// Checksum algorithms that Wikimedia publishes for its dumps
+#[derive(Debug, Clone, Copy)]
+enum Algorithm {
+    Md5,
+    Sha1,
+}
+
+/// Find the published checksum of the dump, in a `*md5sums*` or `*sha1sums*` file
+/// (e.g. `wikidata-20250101-md5sums.txt`) in the same directory. SHA-1 is preferred.
+fn find_checksum(path: &Path) -> Result<Option<(Algorithm, String)>, ProcessingError> {
+    let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(None);
+    };
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut found = None;
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_lowercase();
+        let algorithm = if name.contains("sha1sums") {
+            Algorithm::Sha1
+        } else if name.contains("md5sums") {
+            Algorithm::Md5
+        } else {
+            continue;
+        };
+        // Each line holds a hex digest and a file name, the latter marked with `*` in binary mode
+        let checksum = fs::read_to_string(entry.path())?
+            .lines()
+            .filter_map(|line| line.split_once(char::is_whitespace))
+            .find(|(_, name)| name.trim().trim_start_matches('*') == file_name)
+            .map(|(digest, _)| digest.to_lowercase());
+        if let Some(checksum) = checksum {
+            if matches!(algorithm, Algorithm::Sha1) || found.is_none() {
+                found = Some((algorithm, checksum));
+            }
+        }
+    }
+    Ok(found)
+}
+
+fn hex_digest<D: Digest>(mut file: File) -> io::Result<String> {
+    let mut hasher = D::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Verify the dump against its published checksum on a background thread, so hashing
+/// overlaps with processing. Fails right away when no checksum is published for the dump.
+pub fn verify_checksum(
+    path: &Path,
+) -> Result<JoinHandle<Result<(), ProcessingError>>, ProcessingError> {
+    let Some((algorithm, expected)) = find_checksum(path)? else {
+        return Err(ProcessingError::ChecksumError(format!(
+            "no md5sums or sha1sums file with a checksum for {} found",
+            path.display()
+        )));
+    };
+    let file = File::open(path)?;
+    let path = path.to_path_buf();
+    Ok(thread::spawn(move || {
+        let actual = match algorithm {
+            Algorithm::Md5 => hex_digest::<Md5>(file)?,
+            Algorithm::Sha1 => hex_digest::<Sha1>(file)?,
+        };
+        if actual != expected {
+            return Err(ProcessingError::ChecksumError(format!(
+                "{:?} checksum of {} is {}, expected {}",
+                algorithm,
+                path.display(),
+                actual,
+                expected
+            )));
+        }
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_the_start_of_the_dump() {
+        assert!(check_start(Some(b"[")).is_ok());
+        assert!(check_start(Some(b"{\"id\":\"Q1\"},")).is_err());
+        assert!(check_start(None).is_err());
+    }
+
+    #[test]
+    fn checks_the_end_of_the_dump() {
+        assert!(check_end(&[b"{\"id\":\"Q1\"}", b"]"]).is_ok());
+        // An empty array
+        assert!(check_end(&[b"[", b"]"]).is_ok());
+        assert!(check_end(&[b"]"]).is_ok());
+        // Truncated in the middle of an entity, or before the closing bracket
+        assert!(check_end(&[b"{\"id\":\"Q1\"},", b"{\"id\":\"Q2"]).is_err());
+        assert!(check_end(&[b"{\"id\":\"Q1\"},", b"{\"id\":\"Q2\"},"]).is_err());
+        assert!(check_end(&[b"{\"id\":", b"]"]).is_err());
+        assert!(check_end(&[]).is_err());
+    }
+
+    #[test]
+    fn checks_the_structure_of_uncompressed_dumps() {
+        assert!(check_structure(b"\n[\n{\"id\":\"Q1\"},\n{\"id\":\"Q2\"}\n]\n\n").is_ok());
+        assert!(check_structure(b"[\n{\"id\":\"Q1\"},\n{\"id\":").is_err());
+        assert!(check_structure(b"").is_err());
+    }
+
+    #[test]
+    fn keeps_the_last_two_non_blank_lines() {
+        let mut tail = DumpTail::default();
+        for line in ["[", "{\"id\":\"Q1\"},", "{\"id\":\"Q2\"}", "  ", "]", ""] {
+            tail.push(line.as_bytes());
+        }
+        assert_eq!(tail.lines, vec![b"{\"id\":\"Q2\"}".to_vec(), b"]".to_vec()]);
+        assert!(tail.check().is_ok());
+
+        let mut truncated = DumpTail::default();
+        for line in ["[", "{\"id\":\"Q1\"},", "{\"id\":"] {
+            truncated.push(line.as_bytes());
+        }
+        assert!(truncated.check().is_err());
+    }
+}
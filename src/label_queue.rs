@@ -0,0 +1,283 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::label_source::{Failure, LabelSource, Labels};
+use crate::processing_error::ProcessingError;
+
+/// What a source returned for an ID
+#[derive(Clone)]
+enum Outcome {
+    Labels(Vec<String>),
+    /// The source does not know the ID
+    Unknown,
+    /// The request for the ID failed
+    Failed(String),
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// IDs that are waiting to be requested, in order
+    pending: Vec<String>,
+    /// IDs that are pending or being requested
+    queued: HashSet<String>,
+    /// Outcomes of the requested IDs, until all workers waiting for them have taken them
+    outcomes: HashMap<String, Outcome>,
+    /// Number of workers waiting for each ID
+    waiting: HashMap<String, usize>,
+    /// Number of requests to the source in progress
+    in_flight: usize,
+}
+
+/// How long a worker waits for other workers to fill a batch, before it is requested anyway
+pub const LINGER: Duration = Duration::from_millis(50);
+
+/// Collects the IDs that workers ask for into full batches for a source, such as the MediaWiki
+/// API, so a worker that needs a single ID does not send a request for it alone. A batch is
+/// requested by one of the waiting workers once it is full, or once a worker has waited
+/// `linger` for more IDs. The outcomes are handed back to all workers waiting for them.
+pub struct LabelQueue {
+    source: Box<dyn LabelSource>,
+    batch_size: usize,
+    /// Maximum number of requests to the source at the same time
+    max_in_flight: usize,
+    linger: Duration,
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+impl LabelQueue {
+    pub fn new(
+        source: Box<dyn LabelSource>,
+        batch_size: usize,
+        max_in_flight: usize,
+        linger: Duration,
+    ) -> Self {
+        Self {
+            source,
+            batch_size,
+            max_in_flight: max_in_flight.max(1),
+            linger,
+            state: Mutex::new(QueueState::default()),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Request a batch of IDs from the source, without holding the lock, and record the outcomes
+    fn request(&self, batch: Vec<String>) {
+        let ids: Vec<&str> = batch.iter().map(String::as_str).collect();
+        let Labels {
+            mut labels,
+            failure,
+        } = self.source.labels(&ids);
+        let (failed, error) = match failure {
+            Some(Failure { ids, error }) => {
+                let error = match error {
+                    ProcessingError::NetworkError(error) => error,
+                    error => error.to_string(),
+                };
+                (ids.into_iter().collect(), error)
+            }
+            None => (HashSet::new(), String::new()),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        for id in batch {
+            state.queued.remove(&id);
+            let outcome = match labels.remove(&id) {
+                Some(id_labels) => Outcome::Labels(id_labels),
+                None if failed.contains(&id) => Outcome::Failed(error.clone()),
+                None => Outcome::Unknown,
+            };
+            state.outcomes.insert(id, outcome);
+        }
+        self.changed.notify_all();
+    }
+}
+
+impl LabelSource for LabelQueue {
+    fn labels(&self, ids: &[&str]) -> Labels {
+        let mut state = self.state.lock().unwrap();
+        for id in ids {
+            *state.waiting.entry(id.to_string()).or_default() += 1;
+            if !state.queued.contains(*id) && !state.outcomes.contains_key(*id) {
+                state.queued.insert(id.to_string());
+                state.pending.push(id.to_string());
+            }
+        }
+
+        let deadline = Instant::now() + self.linger;
+        loop {
+            if ids.iter().all(|id| state.outcomes.contains_key(*id)) {
+                break;
+            }
+            // Request a batch when it is full, or when there are no more IDs to wait for
+            let now = Instant::now();
+            if state.in_flight < self.max_in_flight
+                && (state.pending.len() >= self.batch_size
+                    || (now >= deadline && !state.pending.is_empty()))
+            {
+                let size = state.pending.len().min(self.batch_size);
+                let batch: Vec<String> = state.pending.drain(..size).collect();
+                state.in_flight += 1;
+                drop(state);
+                self.request(batch);
+                state = self.state.lock().unwrap();
+                continue;
+            }
+            let wait = if now < deadline {
+                deadline - now
+            } else {
+                self.linger
+            };
+            state = self.changed.wait_timeout(state, wait).unwrap().0;
+        }
+
+        // Take the outcomes, and forget them once no other worker is waiting for them
+        let mut labels = HashMap::new();
+        let mut failed = Vec::new();
+        let mut error = None;
+        for id in ids {
+            let outcome = state.outcomes[*id].clone();
+            let waiting = state.waiting.get_mut(*id).unwrap();
+            *waiting -= 1;
+            if *waiting == 0 {
+                state.waiting.remove(*id);
+                state.outcomes.remove(*id);
+            }
+            match outcome {
+                Outcome::Labels(id_labels) => {
+                    labels.insert(id.to_string(), id_labels);
+                }
+                Outcome::Unknown => {}
+                Outcome::Failed(e) => {
+                    failed.push(id.to_string());
+                    error = Some(e);
+                }
+            }
+        }
+        Labels {
+            labels,
+            failure: error.map(|error| Failure {
+                ids: failed,
+                error: ProcessingError::NetworkError(error),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::label_source::MemorySource;
+    use std::thread;
+
+    fn ids(worker: usize) -> Vec<String> {
+        (0..5).map(|id| format!("Q{}", worker * 5 + id)).collect()
+    }
+
+    #[test]
+    fn collects_the_ids_of_workers_into_batches() {
+        let all_ids: Vec<String> = (0..40).map(|id| format!("Q{}", id)).collect();
+        let labels: Vec<(&str, &[&str])> = all_ids
+            .iter()
+            .map(|id| (id.as_str(), &["label"][..]))
+            .collect();
+        let source = MemorySource::new(&labels);
+        let requested = source.requested.clone();
+        // The batch is full once all workers have added their IDs
+        let queue = LabelQueue::new(Box::new(source), 40, 1, Duration::from_secs(5));
+        thread::scope(|scope| {
+            for worker in 0..8 {
+                let queue = &queue;
+                scope.spawn(move || {
+                    let ids = ids(worker);
+                    let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+                    let labels = queue.labels(&ids).labels;
+                    assert_eq!(labels.len(), 5);
+                });
+            }
+        });
+        // A single request for the IDs of all workers
+        let requested = requested.lock().unwrap();
+        assert_eq!(requested.len(), 1);
+        assert_eq!(requested[0].len(), 40);
+        assert!(queue.state.lock().unwrap().outcomes.is_empty());
+    }
+
+    #[test]
+    fn requests_each_id_once() {
+        let source = MemorySource::new(&[("Q1", &["one"]), ("Q2", &["two"])]);
+        let requested = source.requested.clone();
+        let queue = LabelQueue::new(Box::new(source), 50, 1, Duration::from_millis(100));
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let labels = queue.labels(&["Q1", "Q2", "Q3"]).labels;
+                    assert_eq!(labels["Q1"], vec!["one"]);
+                    assert!(!labels.contains_key("Q3"));
+                });
+            }
+        });
+        let mut requested = requested.lock().unwrap().concat();
+        requested.sort();
+        assert_eq!(requested, vec!["Q1", "Q2", "Q3"]);
+    }
+
+    #[test]
+    fn requests_partial_batches_after_lingering() {
+        let source = MemorySource::new(&[("Q1", &["one"])]);
+        let queue = LabelQueue::new(Box::new(source), 50, 1, Duration::from_millis(10));
+        let labels = queue.labels(&["Q1"]).labels;
+        assert_eq!(labels["Q1"], vec!["one"]);
+    }
+
+    /// Whether the queue has forgotten all IDs
+    fn is_idle(queue: &LabelQueue) -> bool {
+        let state = queue.state.lock().unwrap();
+        state.pending.is_empty()
+            && state.queued.is_empty()
+            && state.outcomes.is_empty()
+            && state.waiting.is_empty()
+            && state.in_flight == 0
+    }
+
+    #[test]
+    fn returns_the_labels_of_the_batches_that_did_not_fail() {
+        let labels: Vec<(String, [&str; 1])> =
+            (0..12).map(|id| (format!("Q{}", id), ["label"])).collect();
+        let labels: Vec<(&str, &[&str])> = labels
+            .iter()
+            .map(|(id, labels)| (id.as_str(), &labels[..]))
+            .collect();
+        let source = || MemorySource::new(&labels).failing(&["Q3"]);
+
+        // The batch of Q3 and Q4 fails
+        let queue = LabelQueue::new(Box::new(source()), 2, 1, Duration::from_millis(10));
+        let result = queue.labels(&["Q1", "Q2", "Q3", "Q4"]);
+        let mut resolved: Vec<&String> = result.labels.keys().collect();
+        resolved.sort();
+        assert_eq!(resolved, ["Q1", "Q2"]);
+        assert_eq!(result.failed(), ["Q3", "Q4"]);
+        assert!(is_idle(&queue));
+
+        // Workers waiting for a failed batch are woken up, and the failure is forgotten
+        let queue = LabelQueue::new(Box::new(source()), 3, 2, Duration::from_millis(10));
+        thread::scope(|scope| {
+            for worker in 0..8 {
+                let queue = &queue;
+                scope.spawn(move || {
+                    let ids: Vec<String> = (0..4).map(|id| format!("Q{}", worker + id)).collect();
+                    let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+                    let result = queue.labels(&ids);
+                    assert_eq!(result.labels.len() + result.failed().len(), ids.len());
+                    if ids.contains(&"Q3") {
+                        assert!(result.failed().contains(&"Q3".to_string()));
+                    }
+                });
+            }
+        });
+        assert!(is_idle(&queue));
+    }
+}
@@ -0,0 +1,556 @@
+use reqwest::blocking::{Client, Response};
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{language_chain, term_languages, Config};
+use crate::entity_resolver::EntityCache;
+use crate::processing_error::ProcessingError;
+
+/// The MediaWiki API of Wikidata
+const WIKIDATA_API_URL: &str = "https://www.wikidata.org/w/api.php";
+/// Number of IDs per request to the MediaWiki API, the maximum of `wbgetentities`
+pub const API_BATCH_SIZE: usize = 50;
+/// Wait before the first retry of a request, doubled for every next retry
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait before retrying a request, also when the API asks to wait longer
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How long requests fail right away after a request failed after all its retries, so the
+/// entities that follow do not each wait through the backoff while the API is down
+const CIRCUIT_BREAK: Duration = Duration::from_secs(60);
+
+/// IDs that could not be looked up because the request for them failed
+#[derive(Debug)]
+pub struct Failure {
+    pub ids: Vec<String>,
+    /// The last error
+    pub error: ProcessingError,
+}
+
+/// Labels that a source returned, and the IDs it could not look up
+#[derive(Debug, Default)]
+pub struct Labels {
+    /// Labels of the IDs the source knows, in the languages of the source, in order
+    pub labels: HashMap<String, Vec<String>>,
+    pub failure: Option<Failure>,
+}
+
+impl Labels {
+    /// IDs that could not be looked up
+    #[cfg(test)]
+    pub fn failed(&self) -> &[String] {
+        self.failure.as_ref().map_or(&[], |failure| &failure.ids)
+    }
+
+    /// Add the labels and the failed IDs of another lookup
+    pub fn extend(&mut self, other: Labels) {
+        self.labels.extend(other.labels);
+        self.failure = match (self.failure.take(), other.failure) {
+            (Some(mut failure), Some(other)) => {
+                failure.ids.extend(other.ids);
+                failure.error = other.error;
+                Some(failure)
+            }
+            (failure, other) => failure.or(other),
+        };
+    }
+}
+
+impl From<HashMap<String, Vec<String>>> for Labels {
+    fn from(labels: HashMap<String, Vec<String>>) -> Self {
+        Self {
+            labels,
+            failure: None,
+        }
+    }
+}
+
+/// A source of the labels of entities
+pub trait LabelSource: Send + Sync {
+    /// Labels of the IDs in the languages of the source, in order, of the IDs the source knows.
+    /// A label is empty when the entity has no label in that language. When some of the IDs
+    /// cannot be looked up, the labels of the others are still returned.
+    fn labels(&self, ids: &[&str]) -> Labels;
+}
+
+/// Settings of the requests to the MediaWiki API
+pub struct RequestSettings {
+    pub timeout: Duration,
+    /// Number of times a failed request is retried
+    pub max_retries: u32,
+    /// Seconds of replication lag at which the API refuses requests
+    pub maxlag: u32,
+    pub user_agent: String,
+}
+
+/// Labels from the `wbgetentities` action of the MediaWiki API of a Wikibase, e.g. Wikidata
+pub struct MediaWikiApi {
+    client: Client,
+    /// Wikibase API url
+    api_base_url: String,
+    /// Required languages, the first one being the primary language
+    languages: Vec<String>,
+    /// Fallback languages, in order, for labels missing in a required language
+    fallback: Vec<String>,
+    /// Languages to request from the API: the required languages and the fallback languages
+    api_languages: String,
+    max_retries: u32,
+    maxlag: String,
+    /// Until when requests fail right away, after a request failed after all its retries
+    broken_until: Mutex<Option<Instant>>,
+}
+
+impl MediaWikiApi {
+    pub fn new(
+        api_base_url: String,
+        languages: &[String],
+        fallback: &[String],
+        settings: RequestSettings,
+    ) -> Result<Self, ProcessingError> {
+        let client = Client::builder()
+            .timeout(settings.timeout)
+            .user_agent(settings.user_agent)
+            .build()
+            .map_err(|e| {
+                ProcessingError::NetworkError(format!("cannot create the HTTP client: {}", e))
+            })?;
+        Ok(Self {
+            client,
+            api_base_url,
+            languages: languages.to_vec(),
+            fallback: fallback.to_vec(),
+            api_languages: term_languages(languages, fallback).join("|"),
+            max_retries: settings.max_retries,
+            maxlag: settings.maxlag.to_string(),
+            broken_until: Mutex::new(None),
+        })
+    }
+
+    /// The API of Wikidata, with the languages and request settings of the configuration
+    pub fn wikidata(config: &Config) -> Result<Self, ProcessingError> {
+        let settings = RequestSettings {
+            timeout: Duration::from_secs(config.request_timeout),
+            max_retries: config.max_retries,
+            maxlag: config.maxlag,
+            user_agent: config.user_agent.clone(),
+        };
+        Self::new(
+            WIKIDATA_API_URL.to_string(),
+            &config.languages,
+            &config.fallback,
+            settings,
+        )
+    }
+
+    /// Request the entities of a batch of IDs. Failed requests, responses with HTTP status 429
+    /// (too many requests) or 5xx, and maxlag errors are retried with exponential backoff,
+    /// waiting as long as the Retry-After header asks instead, if it is there. Once a request
+    /// has failed after all its retries, requests fail right away for a while.
+    fn request(&self, ids: &[&str]) -> Result<Value, ProcessingError> {
+        if let Some(broken_until) = *self.broken_until.lock().unwrap() {
+            let now = Instant::now();
+            if now < broken_until {
+                return Err(ProcessingError::NetworkError(format!(
+                    "the API is not asked again for {} seconds, after a request failed",
+                    (broken_until - now).as_secs()
+                )));
+            }
+        }
+        let ids_param = ids.join("|");
+        let mut attempt = 0;
+        loop {
+            let sent = self
+                .client
+                .get(&self.api_base_url)
+                .query(&[
+                    ("action", "wbgetentities"),
+                    ("format", "json"),
+                    ("ids", &ids_param),
+                    ("props", "labels"),
+                    ("languages", &self.api_languages),
+                    ("maxlag", &self.maxlag),
+                ])
+                .send();
+            let (error, wait) = match sent {
+                Err(e) => (e.to_string(), None),
+                Ok(response) => {
+                    let status = response.status();
+                    let wait = retry_after(&response);
+                    if status.is_success() {
+                        match response.json::<Value>() {
+                            Ok(json) => match json["error"]["code"].as_str() {
+                                None => return Ok(json),
+                                Some("maxlag") => (format!("{}", json["error"]["info"]), wait),
+                                Some(code) => {
+                                    return Err(ProcessingError::NetworkError(format!(
+                                        "API error {}: {}",
+                                        code, json["error"]["info"]
+                                    )))
+                                }
+                            },
+                            // E.g. a response that was cut off
+                            Err(e) => (e.to_string(), None),
+                        }
+                    } else if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+                        (format!("HTTP status {}", status), wait)
+                    } else {
+                        return Err(ProcessingError::NetworkError(format!(
+                            "HTTP status {}",
+                            status
+                        )));
+                    }
+                }
+            };
+            if attempt >= self.max_retries {
+                // Logged once, as the lookups that fail right away are only counted
+                eprintln!(
+                    "Request to the Wikidata API failed after {} retries: {}, so it is not asked again for {} seconds",
+                    attempt,
+                    error,
+                    CIRCUIT_BREAK.as_secs()
+                );
+                *self.broken_until.lock().unwrap() = Some(Instant::now() + CIRCUIT_BREAK);
+                return Err(ProcessingError::NetworkError(format!(
+                    "{} (after {} retries)",
+                    error, attempt
+                )));
+            }
+            let backoff = INITIAL_BACKOFF.saturating_mul(2u32.saturating_pow(attempt));
+            thread::sleep(wait.unwrap_or(backoff).min(MAX_BACKOFF));
+            attempt += 1;
+        }
+    }
+}
+
+/// The number of seconds to wait according to the Retry-After header of a response
+fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    Some(Duration::from_secs(seconds.trim().parse().ok()?))
+}
+
+impl LabelSource for MediaWikiApi {
+    fn labels(&self, ids: &[&str]) -> Labels {
+        let mut labels = HashMap::new();
+        for (index, batch) in ids.chunks(API_BATCH_SIZE).enumerate() {
+            let json = match self.request(batch) {
+                Ok(json) => json,
+                // The labels of the batches before are kept, and the remaining IDs fail
+                Err(error) => {
+                    let ids = ids[index * API_BATCH_SIZE..]
+                        .iter()
+                        .map(|id| id.to_string())
+                        .collect();
+                    return Labels {
+                        labels,
+                        failure: Some(Failure { ids, error }),
+                    };
+                }
+            };
+            if let Some(entities) = json["entities"].as_object() {
+                for (id, entity) in entities {
+                    // Follow the fallback chain, with an empty label when there is none
+                    let entity_labels = self
+                        .languages
+                        .iter()
+                        .map(|lang| {
+                            language_chain(lang, &self.fallback)
+                                .find_map(|lang| entity["labels"][lang]["value"].as_str())
+                                .unwrap_or("")
+                                .to_string()
+                        })
+                        .collect();
+                    labels.insert(id.clone(), entity_labels);
+                }
+            }
+        }
+        Labels::from(labels)
+    }
+}
+
+/// Labels from an existing entity cache, e.g. `output/entity_cache.csv` of an earlier run
+pub struct CacheFile {
+    cache: EntityCache,
+    languages: Vec<String>,
+}
+
+impl CacheFile {
+    /// Load the cache, which is empty when the file does not exist or cannot be read
+    pub fn load(path: &Path, languages: &[String]) -> Self {
+        Self {
+            cache: EntityCache::load_from_csv(path, &languages[0]).unwrap_or_default(),
+            languages: languages.to_vec(),
+        }
+    }
+}
+
+impl LabelSource for CacheFile {
+    fn labels(&self, ids: &[&str]) -> Labels {
+        ids.iter()
+            .filter_map(|id| Some((id.to_string(), self.cache.get(id, &self.languages)?)))
+            .collect::<HashMap<_, _>>()
+            .into()
+    }
+}
+
+/// Sources that are asked in order, each for the IDs that the sources before it do not know
+pub struct ChainedSource {
+    sources: Vec<Box<dyn LabelSource>>,
+}
+
+impl ChainedSource {
+    pub fn new(sources: Vec<Box<dyn LabelSource>>) -> Self {
+        Self { sources }
+    }
+}
+
+impl LabelSource for ChainedSource {
+    fn labels(&self, ids: &[&str]) -> Labels {
+        let mut labels = Labels::default();
+        let mut missing = ids.to_vec();
+        for source in &self.sources {
+            if missing.is_empty() {
+                break;
+            }
+            labels.extend(source.labels(&missing));
+            missing.retain(|id| !labels.labels.contains_key(*id));
+        }
+        // IDs that failed in one source, but that a later source knows, did not fail after all
+        if let Some(failure) = &mut labels.failure {
+            failure.ids.retain(|id| !labels.labels.contains_key(id));
+            if failure.ids.is_empty() {
+                labels.failure = None;
+            }
+        }
+        labels
+    }
+}
+
+/// Labels in memory, which records the IDs it is asked for, per request. Looking up any of
+/// the failing IDs fails for all IDs of the request.
+#[cfg(test)]
+pub struct MemorySource {
+    labels: HashMap<String, Vec<String>>,
+    failing: Vec<String>,
+    pub requested: std::sync::Arc<Mutex<Vec<Vec<String>>>>,
+}
+
+#[cfg(test)]
+impl MemorySource {
+    pub fn new(labels: &[(&str, &[&str])]) -> Self {
+        Self {
+            labels: labels
+                .iter()
+                .map(|(id, labels)| {
+                    let labels = labels.iter().map(|label| label.to_string()).collect();
+                    (id.to_string(), labels)
+                })
+                .collect(),
+            failing: Vec::new(),
+            requested: Default::default(),
+        }
+    }
+
+    pub fn failing(self, ids: &[&str]) -> Self {
+        Self {
+            failing: ids.iter().map(|id| id.to_string()).collect(),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+impl LabelSource for MemorySource {
+    fn labels(&self, ids: &[&str]) -> Labels {
+        let mut requested = self.requested.lock().unwrap();
+        requested.push(ids.iter().map(|id| id.to_string()).collect());
+        let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        if ids.iter().any(|id| self.failing.contains(id)) {
+            return Labels {
+                labels: HashMap::new(),
+                failure: Some(Failure {
+                    ids,
+                    error: ProcessingError::NetworkError("unavailable".to_string()),
+                }),
+            };
+        }
+        ids.into_iter()
+            .filter_map(|id| {
+                let labels = self.labels.get(&id)?.clone();
+                Some((id, labels))
+            })
+            .collect::<HashMap<_, _>>()
+            .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    #[test]
+    fn chained_sources_fall_back_in_order() {
+        let chained = ChainedSource::new(vec![
+            Box::new(MemorySource::new(&[("Q1", &["first"]), ("Q2", &[""])])),
+            Box::new(MemorySource::new(&[
+                ("Q2", &["second"]),
+                ("Q3", &["third"]),
+            ])),
+        ]);
+        let labels = chained.labels(&["Q1", "Q2", "Q3", "Q4"]).labels;
+        assert_eq!(labels["Q1"], vec!["first"]);
+        // An empty label is known, so the next source is not asked
+        assert_eq!(labels["Q2"], vec![""]);
+        assert_eq!(labels["Q3"], vec!["third"]);
+        assert!(!labels.contains_key("Q4"));
+    }
+
+    #[test]
+    fn chained_sources_are_only_asked_for_missing_ids() {
+        let second = MemorySource::new(&[("Q2", &["second"])]);
+        let requested = second.requested.clone();
+        let chained = ChainedSource::new(vec![
+            Box::new(MemorySource::new(&[("Q1", &["first"])])),
+            Box::new(second),
+        ]);
+        chained.labels(&["Q1", "Q2"]);
+        assert_eq!(*requested.lock().unwrap(), vec![vec!["Q2"]]);
+    }
+
+    #[test]
+    fn chained_sources_only_fail_for_ids_that_no_source_knows() {
+        let chained = ChainedSource::new(vec![
+            Box::new(MemorySource::new(&[("Q1", &["first"])]).failing(&["Q1"])),
+            Box::new(MemorySource::new(&[("Q2", &["second"])])),
+        ]);
+        let labels = chained.labels(&["Q1", "Q2", "Q3"]);
+        assert_eq!(labels.labels.len(), 1);
+        assert_eq!(labels.labels["Q2"], vec!["second"]);
+        assert_eq!(labels.failed(), ["Q1", "Q3"]);
+    }
+
+    /// Serve the responses on a local port, one per connection, and send each request to the
+    /// channel. Returns the API url.
+    fn serve(responses: Vec<String>) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/w/api.php", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                while !request.ends_with("\r\n\r\n") {
+                    if reader.read_line(&mut request).unwrap() == 0 {
+                        break;
+                    }
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+                sender.send(request).unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    fn response(status: &str, headers: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}",
+            status,
+            body.len(),
+            headers,
+            body
+        )
+    }
+
+    fn api(url: String, max_retries: u32) -> MediaWikiApi {
+        let settings = RequestSettings {
+            timeout: Duration::from_secs(5),
+            max_retries,
+            maxlag: 5,
+            user_agent: "test/1.0 (test@example.org)".to_string(),
+        };
+        let languages = vec!["en".to_string()];
+        MediaWikiApi::new(url, &languages, &["mul".to_string()], settings).unwrap()
+    }
+
+    const ENTITIES: &str = r#"{"entities":{"Q5":{"labels":{"mul":{"value":"human"}}}}}"#;
+
+    #[test]
+    fn retries_after_too_many_requests_and_maxlag() {
+        let maxlag = r#"{"error":{"code":"maxlag","info":"Waiting for a database server"}}"#;
+        let (url, requests) = serve(vec![
+            response("429 Too Many Requests", "Retry-After: 0\r\n", ""),
+            response("200 OK", "Retry-After: 0\r\n", maxlag),
+            response("200 OK", "", ENTITIES),
+        ]);
+        let labels = api(url, 2).labels(&["Q5"]);
+        assert_eq!(labels.labels["Q5"], vec!["human"]);
+        assert!(labels.failure.is_none());
+        let requests: Vec<String> = requests.iter().collect();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[0].contains("maxlag=5"));
+        assert!(requests[0]
+            .to_lowercase()
+            .contains("user-agent: test/1.0 (test@example.org)"));
+    }
+
+    #[test]
+    fn fails_after_the_retries() {
+        let (url, _requests) = serve(vec![
+            response("503 Service Unavailable", "Retry-After: 0\r\n", ""),
+            response("503 Service Unavailable", "Retry-After: 0\r\n", ""),
+        ]);
+        let labels = api(url, 1).labels(&["Q5"]);
+        assert!(labels.labels.is_empty());
+        let failure = labels.failure.unwrap();
+        assert_eq!(failure.ids, ["Q5"]);
+        assert!(matches!(failure.error, ProcessingError::NetworkError(_)));
+    }
+
+    #[test]
+    fn does_not_retry_client_errors() {
+        let (url, requests) = serve(vec![response("400 Bad Request", "", "")]);
+        let labels = api(url, 3).labels(&["Q5"]);
+        assert_eq!(labels.failed(), ["Q5"]);
+        assert_eq!(requests.iter().count(), 1);
+    }
+
+    #[test]
+    fn keeps_the_labels_of_the_batches_before_a_failure() {
+        let (url, requests) = serve(vec![
+            response("200 OK", "", ENTITIES),
+            response("503 Service Unavailable", "Retry-After: 0\r\n", ""),
+        ]);
+        let ids: Vec<String> = (5..5 + 2 * API_BATCH_SIZE as u32 + 1)
+            .map(|id| format!("Q{}", id))
+            .collect();
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        let labels = api(url, 0).labels(&ids);
+        assert_eq!(labels.labels["Q5"], vec!["human"]);
+        // The second batch failed, so the third one is not requested
+        assert_eq!(labels.failed(), &ids[API_BATCH_SIZE..]);
+        assert_eq!(requests.iter().count(), 2);
+    }
+
+    #[test]
+    fn does_not_ask_the_api_again_right_after_a_failure() {
+        let (url, requests) = serve(vec![
+            response("503 Service Unavailable", "Retry-After: 0\r\n", ""),
+            response("200 OK", "", ENTITIES),
+        ]);
+        let api = api(url, 0);
+        assert_eq!(api.labels(&["Q5"]).failed(), ["Q5"]);
+        assert_eq!(api.labels(&["Q5"]).failed(), ["Q5"]);
+        // Only the first lookup sent a request
+        requests.recv().unwrap();
+        assert!(requests.try_recv().is_err());
+    }
+}
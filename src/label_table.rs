@@ -0,0 +1,328 @@
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::batched_writer::OutputPaths;
+use crate::config::Config;
+use crate::entity_resolver::{entity_ids, EntityResolver};
+use crate::label_source::{CacheFile, ChainedSource, LabelSource, Labels};
+use crate::processing_error::ProcessingError;
+
+/// Keys of the properties of a GeoJSON feature that are not entity properties
+const FEATURE_KEYS: [&str; 3] = ["type", "label", "descr"];
+
+/// Labels of the entities in the dump, to resolve IDs without the Wikidata API. The table is
+/// written as a side output of the extraction, as rows of ID, label and language, like the
+/// entity cache. An entity without a label in any of the languages has a single empty label.
+pub struct LabelTable {
+    /// Labels by ID and language
+    labels: HashMap<String, HashMap<String, String>>,
+    languages: Vec<String>,
+}
+
+impl LabelTable {
+    /// The rows of an entity in the table, given its label in each of the languages
+    pub fn rows(
+        entity_id: &str,
+        labels: &[Option<&str>],
+        languages: &[String],
+    ) -> Vec<[String; 3]> {
+        let rows: Vec<[String; 3]> = languages
+            .iter()
+            .zip(labels.iter().copied())
+            .filter_map(|(lang, label)| {
+                Some([entity_id.to_string(), label?.to_string(), lang.clone()])
+            })
+            .collect();
+        if rows.is_empty() {
+            return vec![[entity_id.to_string(), String::new(), languages[0].clone()]];
+        }
+        rows
+    }
+
+    /// Load the labels of the given IDs from the table
+    pub fn load(
+        path: &Path,
+        ids: &HashSet<String>,
+        languages: &[String],
+    ) -> Result<Self, ProcessingError> {
+        let mut labels: HashMap<String, HashMap<String, String>> = HashMap::new();
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_path(path)?;
+        for record in reader.records() {
+            let record = record?;
+            if record.len() == 3 && ids.contains(&record[0]) {
+                labels
+                    .entry(record[0].to_string())
+                    .or_default()
+                    .insert(record[2].to_string(), record[1].to_string());
+            }
+        }
+        Ok(Self {
+            labels,
+            languages: languages.to_vec(),
+        })
+    }
+}
+
+impl LabelSource for LabelTable {
+    /// Labels of the IDs in the table, empty for the languages without a label
+    fn labels(&self, ids: &[&str]) -> Labels {
+        ids.iter()
+            .filter_map(|id| {
+                let labels = self.labels.get(*id)?;
+                let labels = self
+                    .languages
+                    .iter()
+                    .map(|lang| labels.get(lang).cloned().unwrap_or_default())
+                    .collect();
+                Some((id.to_string(), labels))
+            })
+            .collect::<HashMap<_, _>>()
+            .into()
+    }
+}
+
+/// Call a function for each entry of the KV store, in JSON Lines or MessagePack
+fn for_each_kv_entry(
+    path: &Path,
+    output_format: &str,
+    mut f: impl FnMut(Value) -> Result<(), ProcessingError>,
+) -> Result<(), ProcessingError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if output_format == "JSONLines" {
+        for line in reader.lines() {
+            f(serde_json::from_str(&line?)?)?;
+        }
+    } else {
+        while !reader.fill_buf()?.is_empty() {
+            let mut deserializer = rmp_serde::Deserializer::new(&mut reader);
+            f(Value::deserialize(&mut deserializer)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// The properties of each entity of an entry of the KV store
+fn kv_properties(entry: &Value) -> impl Iterator<Item = &Map<String, Value>> {
+    entry
+        .as_object()
+        .into_iter()
+        .flat_map(|entry| entry.values())
+        .filter_map(|entity_data| entity_data.get("props")?.as_object())
+}
+
+/// The IDs that are referenced in the properties of the KV store. The GeoJSON features have
+/// the same properties, so their IDs are among them.
+pub fn referenced_ids(
+    kv_path: &Path,
+    output_format: &str,
+) -> Result<HashSet<String>, ProcessingError> {
+    let mut ids = HashSet::new();
+    for_each_kv_entry(kv_path, output_format, |entry| {
+        for properties in kv_properties(&entry) {
+            let mut referenced = Vec::new();
+            for value in properties.values() {
+                entity_ids(value, &mut referenced);
+            }
+            ids.extend(referenced.into_iter().map(String::from));
+        }
+        Ok(())
+    })?;
+    Ok(ids)
+}
+
+/// Rewrite the properties of the KV store and the GeoJSON features with a function that
+/// resolves their IDs, via temporary files
+pub fn rewrite_properties(
+    paths: &OutputPaths,
+    output_format: &str,
+    resolve: impl Fn(Map<String, Value>) -> Map<String, Value>,
+) -> Result<(), ProcessingError> {
+    let tmp_path = paths.kv.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    for_each_kv_entry(&paths.kv, output_format, |mut entry| {
+        if let Some(entry) = entry.as_object_mut() {
+            for entity_data in entry.values_mut().filter_map(Value::as_object_mut) {
+                if let Some(Value::Object(properties)) = entity_data.remove("props") {
+                    let properties = resolve(properties);
+                    if !properties.is_empty() {
+                        entity_data.insert("props".to_string(), Value::Object(properties));
+                    }
+                }
+            }
+        }
+        if output_format == "JSONLines" {
+            writeln!(writer, "{}", serde_json::to_string(&entry)?)?;
+        } else {
+            writer.write_all(&rmp_serde::encode::to_vec(&entry)?)?;
+        }
+        Ok(())
+    })?;
+    writer.flush()?;
+    drop(writer);
+    fs::rename(tmp_path, &paths.kv)?;
+
+    if let Some(geojson) = &paths.geojson {
+        let tmp_path = geojson.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for line in BufReader::new(File::open(geojson)?).lines() {
+            let mut feature: Value = serde_json::from_str(&line?)?;
+            if let Some(Value::Object(properties)) = feature.get_mut("properties") {
+                // Only the entity properties are resolved, not the type and terms
+                let (terms, entity_properties): (Map<String, Value>, Map<String, Value>) =
+                    std::mem::take(properties)
+                        .into_iter()
+                        .partition(|(key, _)| FEATURE_KEYS.contains(&key.as_str()));
+                *properties = terms;
+                properties.extend(resolve(entity_properties));
+            }
+            writeln!(writer, "{}", serde_json::to_string(&feature)?)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(tmp_path, geojson)?;
+    }
+    Ok(())
+}
+
+/// Second stage of offline resolution: resolve the IDs in the properties of the KV store and
+/// the GeoJSON features from the label table that was written during the extraction. IDs of
+/// entities that are not in the dump are looked up in the entity cache of earlier online runs,
+/// if there is one.
+pub fn resolve_offline(paths: &OutputPaths, config: &Config) -> Result<(), ProcessingError> {
+    let Some(labels_path) = &paths.labels else {
+        return Ok(());
+    };
+    println!("Resolving the IDs from {}", labels_path.display());
+
+    // Only the labels of the IDs that are referenced are loaded
+    let ids = referenced_ids(&paths.kv, &config.output_format)?;
+    let table = LabelTable::load(labels_path, &ids, &config.languages)?;
+    let cache_file = CacheFile::load(
+        Path::new(&format!("{}/entity_cache.csv", config.output_dir)),
+        &config.languages,
+    );
+    let source = ChainedSource::new(vec![Box::new(table), Box::new(cache_file)]);
+    let resolver = EntityResolver::new(None, Box::new(source), &config.languages);
+    rewrite_properties(paths, &config.output_format, |properties| {
+        resolver.resolve_entity_ids(properties)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    /// An empty output directory of a test
+    fn output_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("label_table_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn writes_a_row_per_label() {
+        let languages = strings(&["en", "nl", "de"]);
+        let row = |id: &str, label: &str, lang: &str| [id, label, lang].map(String::from);
+        assert_eq!(
+            LabelTable::rows("Q1", &[Some("Earth"), None, Some("Erde")], &languages),
+            [row("Q1", "Earth", "en"), row("Q1", "Erde", "de")]
+        );
+        // Without any label, a single empty label in the primary language
+        assert_eq!(
+            LabelTable::rows("Q2", &[None, None, None], &languages),
+            [row("Q2", "", "en")]
+        );
+    }
+
+    #[test]
+    fn loads_the_labels_of_the_given_ids() {
+        let dir = output_dir("load");
+        let path = dir.join("labels.csv");
+        fs::write(&path, "Q1,Earth,en\nQ1,Aarde,nl\nQ2,Moon,en\nQ3,,en\n").unwrap();
+        let ids = HashSet::from(["Q1".to_string(), "Q3".to_string()]);
+        let table = LabelTable::load(&path, &ids, &strings(&["en", "nl"])).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let labels = table.labels(&["Q1", "Q2", "Q3"]).labels;
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels["Q1"], strings(&["Earth", "Aarde"]));
+        assert_eq!(labels["Q3"], strings(&["", ""]));
+    }
+
+    /// Extract offline to a KV store in the output format, and resolve it from the label table
+    fn resolves_offline(output_format: &str) {
+        let dir = output_dir(output_format);
+        let mut config = Config::for_test(&dir);
+        config.output_format = output_format.to_string();
+        config.resolver = "offline".to_string();
+        config.geojson = Some("NDJSON".to_string());
+        let paths = OutputPaths::new(&config, None);
+
+        let entry = json!({"Q1": {
+            "label": "Acme",
+            "props": {"P17": "Q55", "P112": ["Q2", "Q404"], "P749": "Q3", "P1813": "ACME"},
+        }});
+        if output_format == "JSONLines" {
+            fs::write(&paths.kv, format!("{}\n", entry)).unwrap();
+        } else {
+            fs::write(&paths.kv, rmp_serde::encode::to_vec(&entry).unwrap()).unwrap();
+        }
+        // The type and terms of a feature are never resolved, even when they look like an ID
+        let feature = json!({"type": "Feature", "id": "Q1", "properties": {
+            "type": "organization", "label": "Q55", "P17": "Q55",
+        }});
+        fs::write(paths.geojson.as_ref().unwrap(), format!("{}\n", feature)).unwrap();
+        // Q3 has no label, and Q404 is not in the dump
+        fs::write(
+            paths.labels.as_ref().unwrap(),
+            "Q55,Netherlands,en\nQ2,Ada Example,en\nQ3,,en\nQ9,Unused,en\n",
+        )
+        .unwrap();
+
+        resolve_offline(&paths, &config).unwrap();
+        let mut entries = Vec::new();
+        for_each_kv_entry(&paths.kv, output_format, |entry| {
+            entries.push(entry);
+            Ok(())
+        })
+        .unwrap();
+        let feature: Value =
+            serde_json::from_str(&fs::read_to_string(paths.geojson.as_ref().unwrap()).unwrap())
+                .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            entries,
+            [json!({"Q1": {
+                "label": "Acme",
+                "props": {"P17": "Netherlands", "P112": ["Ada Example", "Q404"], "P1813": "ACME"},
+            }})]
+        );
+        assert_eq!(
+            feature["properties"],
+            json!({"type": "organization", "label": "Q55", "P17": "Netherlands"})
+        );
+    }
+
+    #[test]
+    fn resolves_json_lines_offline() {
+        resolves_offline("JSONLines");
+    }
+
+    #[test]
+    fn resolves_message_pack_offline() {
+        resolves_offline("MessagePack");
+    }
+}
@@ -1,521 +1,1284 @@
 use rayon::prelude::*;
-use serde::Deserialize;
+use serde_json::value::RawValue;
 use serde_json::{json, Map, Value};
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use std::time::{Duration, Instant};
 
-mod utils;
-use utils::{create_image_thumbnail_url, fetch_base64_image};
 mod batched_writer;
-use batched_writer::BatchedWriter;
+mod utils;
+use batched_writer::{merge_parts, remove_parts, BatchedWriter, OutputPaths};
 mod entity_resolver;
 use entity_resolver::EntityResolver;
 mod processing_error;
 use processing_error::ProcessingError;
 mod config;
-use config::{get_configuration, Config};
-
-#[derive(Debug, Deserialize)]
-struct WikidataEntity {
-    id: String,
-    claims: Option<Map<String, Value>>,
-    labels: Option<Map<String, Value>>,
-    descriptions: Option<Map<String, Value>>,
-    aliases: Option<Map<String, Value>>,
-    // #[serde(default)]
-    // sitelinks: Value,
+mod definitions;
+use config::{first_in_chain, get_configuration, Config};
+use definitions::PropertyDefinition;
+mod dump_reader;
+use dump_reader::{
+    detect_compression, map_dump, open_dump, read_line, split_ranges, Compression, Lines,
+    STREAM_BATCH_SIZE,
+};
+mod progress;
+use progress::{ProgressTracker, PROGRESS_INTERVAL};
+mod checkpoint;
+use checkpoint::{Checkpoint, Checkpointer, RangeCheckpoint};
+mod chains;
+use chains::PropertyChains;
+mod class_hierarchy;
+mod geojson;
+use geojson::{feature, write_feature_collection};
+mod prepass;
+use class_hierarchy::{
+    cached_entity_types, direct_entity_types, expand_entity_types, EntityMappings,
+};
+use prepass::Lookups;
+mod integrity;
+mod label_queue;
+mod label_source;
+use label_queue::{LabelQueue, LINGER};
+mod deferred;
+use deferred::{resolve_deferred, write_referenced_ids};
+mod label_table;
+use integrity::{check_start, check_structure, verify_checksum, DumpTail};
+use label_source::{MediaWikiApi, API_BATCH_SIZE};
+use label_table::{resolve_offline, LabelTable};
+mod parse_report;
+use parse_report::{renumber_rejects, ParseReport, RejectedLine, SkipCounts, SkipReason};
+mod dates;
+mod property_value;
+use property_value::{point_in_time, snak_value, value_with_qualifiers};
+mod units;
+mod wikibase;
+use wikibase::{best_rank_statements, parse_statements};
+mod wikidata_entity;
+use wikidata_entity::{language_values, Alias, Term, WikidataEntity};
+
+/// Names of an entity, by the name of the CSV file they are written to
+type UsedNames = Vec<(String, Vec<String>)>;
+
+/// An entity of one of the requested types, ready to be written
+struct EntityRecord {
+    entity_id: String,
+    used_names: UsedNames,
+    kv_entry: Value,
+    /// GeoJSON feature, if GeoJSON is written and the entity has coordinates
+    feature: Option<Value>,
 }
 
-fn get_entity_type_mappings() -> HashMap<&'static str, Vec<&'static str>> {
-    HashMap::from([
-        // human: https://www.wikidata.org/wiki/Q5
-        ("person", vec!["Q5"]),
-        ("organization", vec!["Q43229"]),
-        ("scientific_organization", vec!["Q16519632"]),
-        ("research_institute", vec!["Q31855"]),
-        ("government_agency", vec!["Q327333"]),
-        ("event", vec!["Q1656682"]),
-        (
-            "mood",
-            vec![
-                "Q331769",   // mood
-                "Q41537118", // emotional state
-                "Q3968640",  // mental state
-                "Q16748867", // basic emotion
-                "Q9415",     // emotions
-                "Q9332",     // behavior
-                "Q60539479", // positive emotion
-                "Q60539481", // negative emotion
-            ],
-        ),
-    ])
+/// The properties of an entity, extracted from its statements
+struct Properties {
+    values: Map<String, Value>,
+    /// Ranks of the properties for which they were requested
+    ranks: Map<String, Value>,
+    /// Number of statements that could not be decoded
+    invalid_statements: u64,
 }
 
-fn get_default_properties() -> HashMap<&'static str, Vec<&'static str>> {
-    let organization_props = vec![
-        "P31",   // Instance of
-        "P17",   // Country
-        "P112",  // Founder
-        "P571",  // Inception date
-        "P1813", // Short name
-        "P18",   // Image
-        "P154",  // Logo
-        "P159",  // Headquarters locations
-        "P856",  // Website
-        "P749",  // Parent organisation
-        "P1454", // Legal form
-        "P3220", // KvK company ID
-        "P452",  // industry
-        "P101",  // field of work
-    ];
-    HashMap::from([
-        (
-            // Person-related properties
-            "person",
-            vec![
-                "P569",  // Date of birth, https://www.wikidata.org/wiki/Property:P569
-                "P570",  // Date of death, https://www.wikidata.org/wiki/Property:P570
-                "P27",   // Country of citizenship
-                "P106",  // Occupation
-                "P18",   // Image
-                "P39",   // Position held
-                "P1449", // Nickname
-                "P101",  // field of work
-            ],
-        ),
-        ("organization", organization_props.clone()),
-        ("scientific_organization", organization_props.clone()),
-        ("research_institute", organization_props.clone()),
-        ("government_agency", organization_props.clone()),
-        (
-            // Event-related properties
-            "event",
-            vec![
-                "P585", // Point in time
-                "P17",  // Country
-                "P276", // Location
-                "P31",  // Instance of
-                "P18",  // Image
-            ],
-        ),
-        (
-            "mood",
-            vec![
-                "P31",   // Instance of
-                "P1552", // Has characteristic
-                "P1889", // Different from
-                "P461",  // Opposite of
-                "P460",  // Said to be the same as
-                "P1382", // Partially coincident with
-                "P18",   // Image
-            ],
-        ),
-    ])
+/// What came of a line of the dump
+#[derive(Default)]
+struct Extraction {
+    records: Vec<EntityRecord>,
+    /// Why the entity was skipped, if it was
+    skipped: Option<SkipReason>,
+    /// Rows of the entity in the label table, when resolving offline
+    label_rows: Vec<[String; 3]>,
+    /// Number of statements of the entity that could not be decoded
+    invalid_statements: u64,
 }
 
+/// Label, description and aliases of an entity in one of the requested languages
+struct Terms<'a> {
+    lang: &'a str,
+    label: Option<&'a str>,
+    description: &'a str,
+    aliases: Vec<&'a str>,
+    /// Languages of the terms that were taken from a fallback language, by field
+    fallbacks: Vec<(&'static str, &'a str)>,
+}
+
+/// Number of entities that are buffered before they are written to disk
+const WRITE_BATCH_SIZE: usize = 10000;
+
 fn process_wikidata(input_path: String, config: Config) -> Result<(), ProcessingError> {
-    let entity_mappings = get_entity_type_mappings();
-    let default_properties = get_default_properties();
-
-    // Create resolver with a specific cache file path
-    let resolver = EntityResolver::new(
-        PathBuf::from(format!("{}/entity_cache.csv", config.output_dir)),
-        "https://www.wikidata.org/w/api.php".to_string(),
-        &config.lang,
-    );
+    let input_path = Path::new(&input_path);
+    // Classes of which the requested entity types are an instance. Unless the subclasses are
+    // cached, the class hierarchy is read in the same first pass as the lookups.
+    let cached_classes = if config.subclasses {
+        cached_entity_types(input_path, &config)?
+    } else {
+        None
+    };
+    let (lookups, hierarchy) = Lookups::read(
+        input_path,
+        &config,
+        config.subclasses && cached_classes.is_none(),
+    )?;
+    let entity_mappings = match (cached_classes, hierarchy) {
+        (Some(entity_mappings), _) => entity_mappings,
+        (None, Some(hierarchy)) => expand_entity_types(&hierarchy, input_path, &config)?,
+        (None, None) => direct_entity_types(&config),
+    };
 
-    // Initialize CSV writers
-    let mut csv_writers: HashMap<String, csv::Writer<File>> = HashMap::new();
-    for entity_type in &config.entity_types {
-        let csv_path = format!("{}/{}.csv", config.output_dir, entity_type);
-        csv_writers.insert(entity_type.clone(), csv::Writer::from_path(csv_path)?);
-    }
+    // Create resolver with a specific cache file path. Resolving offline or deferred, the IDs
+    // are resolved after the extraction instead.
+    let resolver = if config.resolver == "online" {
+        let api = MediaWikiApi::wikidata(&config)?;
+        // The IDs that the workers need are collected into full batches
+        let queue = LabelQueue::new(
+            Box::new(api),
+            API_BATCH_SIZE,
+            config.concurrent_requests,
+            LINGER,
+        );
+        Some(EntityResolver::new(
+            Some(PathBuf::from(format!(
+                "{}/entity_cache.csv",
+                config.output_dir
+            ))),
+            Box::new(queue),
+            &config.languages,
+        ))
+    } else {
+        None
+    };
+
+    let extract =
+        |line: &str| extract_entities(line, resolver.as_ref(), &config, &entity_mappings, &lookups);
 
-    // Create KV store file
-    let kv_file = File::create(format!(
-        "{}/entity_kv_store.{}",
-        config.output_dir,
-        match config.output_format.as_str() {
-            "JSONLines" => "jsonl",
-            _ => "msgpack",
+    let input_size = std::fs::metadata(input_path)?.len();
+    let output_paths = OutputPaths::new(&config, None);
+    let checkpoint_path = PathBuf::from(format!("{}/checkpoint.json", config.output_dir));
+    let checkpoint_interval = Duration::from_secs(config.checkpoint_interval);
+    let checksum = if config.verify_checksum {
+        Some(verify_checksum(input_path)?)
+    } else {
+        None
+    };
+
+    // Save the resolver cache together with each checkpoint
+    let save_checkpoint = |checkpointer: &Checkpointer,
+                           index: usize,
+                           range: RangeCheckpoint,
+                           paths: &OutputPaths|
+     -> Result<(), ProcessingError> {
+        if let Some(resolver) = &resolver {
+            resolver.save_cache();
         }
-    ))?;
-
-    // Create a batched writer
-    let batched_writer =
-        BatchedWriter::new(csv_writers, kv_file, config.output_format.clone(), 10000);
-    let batched_writer = Arc::new(Mutex::new(batched_writer));
-
-    // Open input file and get total file size for progress tracking
-    let file = File::open(input_path).expect("JSON dump file not found");
-    let file_size = file.metadata()?.len();
-    let reader = BufReader::new(file);
-
-    // Progress tracking
-    let start_time = Instant::now();
-    let total_processed = AtomicU64::new(0);
-    let last_reported_promille = AtomicU64::new(0);
-
-    // Process file in parallel
-    reader
-        .lines()
-        .par_bridge()
-        .try_for_each(|line_result| -> Result<(), ProcessingError> {
-            // Read line with thread-safe progress tracking
-            let line = match line_result {
-                Ok(line) => line,
-                Err(e) => return Err(ProcessingError::IoError(e)),
-            };
-
-            // Skip empty or array marker lines
-            if line.trim().is_empty() || line.starts_with('[') || line.starts_with(']') {
-                return Ok(());
-            }
+        checkpointer.record(
+            index,
+            RangeCheckpoint {
+                output_lengths: paths.lengths()?,
+                ..range
+            },
+        )
+    };
+
+    if detect_compression(input_path)? == Compression::None {
+        // Split the uncompressed dump into byte ranges, each parsed by its own worker and
+        // written to its own part files, which are concatenated in order afterwards
+        let data = map_dump(input_path)?;
+        check_structure(&data)?;
+        let checkpoint = if config.resume {
+            Checkpoint::load(
+                &checkpoint_path,
+                &input_path.to_string_lossy(),
+                input_size,
+                &output_paths.file_names(),
+            )?
+        } else {
+            let ranges = split_ranges(&data, rayon::current_num_threads())
+                .into_iter()
+                .map(|range| range.start as u64..range.end as u64);
+            Checkpoint::new(
+                &input_path.to_string_lossy(),
+                input_size,
+                output_paths.file_names(),
+                ranges,
+            )
+        };
+        let range_count = checkpoint.ranges.len();
+        let checkpointer = Checkpointer::new(checkpoint_path, checkpoint);
+        checkpointer.save_current()?;
+        let part_paths: Vec<OutputPaths> = (0..range_count)
+            .map(|part| OutputPaths::new(&config, Some(part)))
+            .collect();
+        let progress = ProgressTracker::new(range_count, data.len() as u64);
+        let report = ParseReport::new(
+            checkpointer
+                .ranges()
+                .iter()
+                .map(|range| range.malformed)
+                .sum(),
+            config.max_errors,
+        );
+
+        part_paths.par_iter().enumerate().try_for_each(
+            |(index, paths)| -> Result<(), ProcessingError> {
+                let range = checkpointer.range(index);
+                let mut writer = if config.resume {
+                    BatchedWriter::resume(
+                        paths,
+                        &range.output_lengths,
+                        config.output_format.clone(),
+                        WRITE_BATCH_SIZE,
+                    )?
+                } else {
+                    BatchedWriter::create(paths, config.output_format.clone(), WRITE_BATCH_SIZE)?
+                };
+                progress.set(index, range.offset - range.start);
+                if range.offset >= range.end {
+                    return Ok(());
+                }
 
-            // Update progress using atomic operations
-            let line_len = line.len() as u64;
-            let current_total = total_processed.fetch_add(line_len, Ordering::Relaxed) + line_len; // it returns the previous value, so add line_len
-            let current_promille = ((current_total as f64 / file_size as f64) * 1000.0) as u64;
-
-            // Report progress with 0.1% granularity
-            let last_promille = last_reported_promille.load(Ordering::Relaxed);
-            if (current_promille - last_promille) >= 1 {
-                // Use compare_exchange to ensure only one thread updates the progress
-                if last_reported_promille
-                    .compare_exchange(
-                        last_promille,
-                        current_promille,
-                        Ordering::SeqCst,
-                        Ordering::Relaxed,
-                    )
-                    .is_ok()
+                let mut last_checkpoint = Instant::now();
+                // Line numbers are counted within the range, and renumbered after merging
+                let mut lines = range.lines;
+                let mut malformed = range.malformed;
+                let mut skipped = range.skipped.clone();
+                for (count, (offset, line)) in
+                    Lines::new(&data, range.offset as usize..range.end as usize).enumerate()
                 {
-                    let elapsed = start_time.elapsed();
-                    let eta = if current_promille > 0 {
-                        let total_estimated_time =
-                            elapsed.as_secs_f64() / (current_promille as f64 / 1000.0);
-                        Duration::from_secs_f64(total_estimated_time - elapsed.as_secs_f64())
-                    } else {
-                        Duration::from_secs(0)
-                    };
-
-                    print!(
-                        "\rProcessing: {:.1}% | Elapsed: {:.0}s | ETA: {:.0}s         ",
-                        current_promille as f64 / 10.0,
-                        elapsed.as_secs(),
-                        eta.as_secs()
-                    );
-                    std::io::stdout().flush()?;
+                    if count % PROGRESS_INTERVAL == 0 {
+                        progress.set(index, offset as u64 - range.start);
+                        progress.report()?;
+                        if last_checkpoint.elapsed() >= checkpoint_interval {
+                            writer.sync()?;
+                            let state = RangeCheckpoint {
+                                offset: offset as u64,
+                                lines,
+                                malformed,
+                                skipped: skipped.clone(),
+                                ..range.clone()
+                            };
+                            save_checkpoint(&checkpointer, index, state, paths)?;
+                            last_checkpoint = Instant::now();
+                        }
+                    }
+                    lines += 1;
+                    let extracted = std::str::from_utf8(line)
+                        .map_err(|e| e.to_string())
+                        .and_then(|line| extract(line).map_err(|e| e.to_string()));
+                    match extracted {
+                        Ok(extraction) => write_extraction(&mut writer, extraction, &mut skipped)?,
+                        Err(error) => {
+                            malformed += 1;
+                            writer.add_reject(&RejectedLine::new(
+                                lines,
+                                offset as u64,
+                                error,
+                                line,
+                            ))?;
+                            report.register()?;
+                        }
+                    }
                 }
+                progress.set(index, range.end - range.start);
+                writer.sync()?;
+                let state = RangeCheckpoint {
+                    offset: range.end,
+                    lines,
+                    malformed,
+                    skipped,
+                    ..range
+                };
+                save_checkpoint(&checkpointer, index, state, paths)
+            },
+        )?;
+
+        merge_parts(&output_paths, &part_paths)?;
+        if let Some(rejects) = &output_paths.rejects {
+            let mut lines_before = 0;
+            let line_bases: Vec<(u64, u64)> = checkpointer
+                .ranges()
+                .iter()
+                .map(|range| {
+                    let base = (range.start, lines_before);
+                    lines_before += range.lines;
+                    base
+                })
+                .collect();
+            renumber_rejects(rejects, &line_bases)?;
+        }
+        let mut skipped = SkipCounts::default();
+        for range in checkpointer.ranges() {
+            skipped.merge(&range.skipped);
+        }
+        // Without a checkpoint, the parts are no longer needed to resume
+        checkpointer.remove()?;
+        remove_parts(&part_paths)?;
+        progress.finish();
+        print_parse_report(&report, &skipped, &output_paths);
+    } else {
+        // Compressed dumps can only be read sequentially, so read batches of lines and
+        // process each batch in parallel, writing the results in order
+        let dump = open_dump(input_path)?;
+        println!("Reading {:?} compressed dump", dump.compression);
+        let checkpoint = if config.resume {
+            Checkpoint::load(
+                &checkpoint_path,
+                &input_path.to_string_lossy(),
+                input_size,
+                &output_paths.file_names(),
+            )?
+        } else {
+            // The range is in decompressed bytes, of which the total is unknown
+            Checkpoint::new(
+                &input_path.to_string_lossy(),
+                input_size,
+                output_paths.file_names(),
+                std::iter::once(0..u64::MAX),
+            )
+        };
+        let checkpointer = Checkpointer::new(checkpoint_path, checkpoint);
+        checkpointer.save_current()?;
+        let range = checkpointer.range(0);
+        let mut writer = if config.resume {
+            BatchedWriter::resume(
+                &output_paths,
+                &range.output_lengths,
+                config.output_format.clone(),
+                WRITE_BATCH_SIZE,
+            )?
+        } else {
+            BatchedWriter::create(
+                &output_paths,
+                config.output_format.clone(),
+                WRITE_BATCH_SIZE,
+            )?
+        };
+        let progress = ProgressTracker::new(1, dump.file_size);
+        let report = ParseReport::new(range.malformed, config.max_errors);
+
+        // Skip the part of the stream that was processed before the checkpoint
+        let mut reader = dump.reader;
+        let mut offset = io::copy(&mut reader.by_ref().take(range.offset), &mut io::sink())?;
+        let mut lines = range.lines;
+        let mut skipped = range.skipped.clone();
+        let mut tail = DumpTail::default();
+        let mut first_batch = range.offset == 0;
+        let mut last_checkpoint = Instant::now();
+        loop {
+            // Lines with their line number and offset, in case they cannot be parsed
+            let mut batch = Vec::with_capacity(STREAM_BATCH_SIZE);
+            while batch.len() < STREAM_BATCH_SIZE {
+                let mut line = Vec::new();
+                let read = read_line(&mut reader, &mut line)?;
+                if read == 0 {
+                    break;
+                }
+                lines += 1;
+                line.truncate(line.trim_ascii_end().len());
+                batch.push((lines, offset, line));
+                offset += read as u64;
+            }
+            if batch.is_empty() {
+                break;
+            }
+            // The start of the dump is checked right away (when resuming, it was checked by
+            // the previous run), but the end only once the whole stream has been read
+            let non_blank: Vec<&[u8]> = batch
+                .iter()
+                .map(|(_, _, line)| line.trim_ascii())
+                .filter(|line| !line.is_empty())
+                .collect();
+            if first_batch {
+                check_start(non_blank.first().copied())?;
+                first_batch = false;
+            }
+            for line in &non_blank[non_blank.len().saturating_sub(2)..] {
+                tail.push(line);
             }
 
-            // Remove trailing comma if present
-            let json_str = line.trim_end_matches(',');
-
-            // Parse entity
-            let entity: WikidataEntity = match serde_json::from_str(json_str) {
-                Ok(e) => e,
-                Err(_) => return Ok(()),
-            };
-            // if let Some(title) = entity.sitelinks["enwiki"]["title"].as_str() {
-            //     dbg!(title);
-            // }
-
-            // Process entity
-            if let (Some(claims), Some(labels), Some(descriptions), Some(aliases)) = (
-                entity.claims,
-                entity.labels,
-                entity.descriptions,
-                entity.aliases,
-            ) {
-                if let Some(label_obj) = labels.get(&config.lang) {
-                    if let Some(label) = label_obj.get("value").and_then(|v| v.as_str()) {
-                        let description = descriptions
-                            .get(&config.lang)
-                            // .or(descriptions.get("en"))
-                            .and_then(|obj| obj.get("value"))
-                            .and_then(|v| v.as_str())
-                            .unwrap_or("");
-                        let aliases = aliases
-                            .get(&config.lang)
-                            .and_then(|value| value.as_array())
-                            .and_then(|values| {
-                                // dbg!(&values);
-                                Some(
-                                    values
-                                        .iter()
-                                        .map(|v| {
-                                            v.get("value").and_then(|v| v.as_str()).unwrap_or("")
-                                        })
-                                        .filter(|alias| *alias != label)
-                                        .collect::<Vec<&str>>(),
-                                )
-                            })
-                            .unwrap_or(Vec::new());
-
-                        for entity_type in &config.entity_types {
-                            if let Some(instance_of) = entity_mappings.get(entity_type.as_str()) {
-                                if claims.get("P31").and_then(|p31| p31.as_array()).map_or(
-                                    false,
-                                    |instances| {
-                                        instances.iter().any(|i| {
-                                            if let Some(instance) =
-                                                i["mainsnak"]["datavalue"]["value"]["id"].as_str()
-                                            {
-                                                instance_of.contains(&instance)
-                                            } else {
-                                                false
-                                            }
-                                        })
-                                    },
-                                ) {
-                                    let (used_names, kv_entry) = prepare_data_export(
-                                        &resolver,
-                                        entity_type,
-                                        &entity.id,
-                                        &claims,
-                                        &config,
-                                        &default_properties,
-                                        label,
-                                        &aliases,
-                                        description,
-                                    );
-
-                                    // Batch the writes
-                                    let mut writer = batched_writer.lock().unwrap();
-                                    write_entity_data(
-                                        &mut writer,
-                                        entity_type,
-                                        &entity.id,
-                                        used_names,
-                                        kv_entry,
-                                    )?;
-                                }
-                            }
-                        }
+            let results: Vec<Result<Extraction, String>> = batch
+                .par_iter()
+                .map(|(_, _, line)| {
+                    let line = std::str::from_utf8(line).map_err(|e| e.to_string())?;
+                    extract(line).map_err(|e| e.to_string())
+                })
+                .collect();
+            for ((line_number, line_offset, line), result) in batch.iter().zip(results) {
+                match result {
+                    Ok(extraction) => write_extraction(&mut writer, extraction, &mut skipped)?,
+                    Err(error) => {
+                        writer.add_reject(&RejectedLine::new(
+                            *line_number,
+                            *line_offset,
+                            error,
+                            line,
+                        ))?;
+                        report.register()?;
                     }
                 }
             }
 
-            Ok(())
-        })?;
+            // Progress is based on the compressed bytes read from disk
+            progress.set(0, dump.bytes_read.load(Ordering::Relaxed));
+            progress.report()?;
+            if last_checkpoint.elapsed() >= checkpoint_interval {
+                writer.sync()?;
+                let state = RangeCheckpoint {
+                    offset,
+                    lines,
+                    malformed: report.malformed(),
+                    skipped: skipped.clone(),
+                    ..range.clone()
+                };
+                save_checkpoint(&checkpointer, 0, state, &output_paths)?;
+                last_checkpoint = Instant::now();
+            }
+        }
+
+        writer.finalize()?;
+        tail.check()?;
+        checkpointer.remove()?;
+        progress.finish();
+        print_parse_report(&report, &skipped, &output_paths);
+    }
+
+    if let Some(resolver) = &resolver {
+        resolver.save_cache();
+        if resolver.unresolved() > 0 {
+            println!(
+                "IDs that could not be resolved because of network errors: {}",
+                resolver.unresolved()
+            );
+        }
+    }
+    resolve_offline(&output_paths, &config)?;
+    write_referenced_ids(&output_paths, &config)?;
+    // Deferred, the features are written as a collection once their IDs are resolved
+    if let (Some(features), Some("FeatureCollection"), false) = (
+        &output_paths.geojson,
+        config.geojson.as_deref(),
+        config.resolver == "deferred",
+    ) {
+        write_feature_collection(features)?;
+    }
 
-    // Final flush of any remaining entries
-    batched_writer.lock().unwrap().finalize()?;
+    if let Some(checksum) = checksum {
+        checksum.join().map_err(|_| {
+            ProcessingError::ChecksumError("the checksum thread panicked".to_string())
+        })??;
+        println!("Checksum verified");
+    }
 
-    // Clear progress line
+    Ok(())
+}
+
+/// Print the number of lines that could not be parsed, and where to find them, and the number
+/// of entities that were skipped for each reason
+fn print_parse_report(report: &ParseReport, skipped: &SkipCounts, output_paths: &OutputPaths) {
+    println!("Malformed lines: {}", report.malformed());
+    if let (Some(rejects), 1..) = (&output_paths.rejects, report.malformed()) {
+        println!("Malformed lines were written to {}", rejects.display());
+    }
+    println!("Skipped entities: {}", skipped.total());
+    println!("  without a requested type: {}", skipped.no_matching_type);
+    println!("  without a label: {}", skipped.no_label);
     println!(
-        "\rProcessing: 100% | Completed in {:.0}s                 ",
-        start_time.elapsed().as_secs()
+        "  with invalid labels, descriptions or aliases: {}",
+        skipped.invalid_terms
     );
+    println!(
+        "Statements of extracted entities that could not be decoded: {}",
+        skipped.invalid_statements
+    );
+}
 
-    Ok(())
+/// Parse a line of the dump, and prepare the data for export for each entity type it matches.
+/// An entity is extracted when it has a label (after falling back) and matches a type.
+/// Returns an error when the line is not a JSON entity.
+fn extract_entities(
+    line: &str,
+    resolver: Option<&EntityResolver>,
+    config: &Config,
+    entity_mappings: &EntityMappings,
+    lookups: &Lookups,
+) -> Result<Extraction, serde_json::Error> {
+    // Skip empty or array marker lines
+    if line.trim().is_empty() || line.starts_with('[') || line.starts_with(']') {
+        return Ok(Extraction::default());
+    }
+
+    // Remove trailing comma if present
+    let json_str = line.trim_end_matches(',');
+
+    // Parse the top-level structure of the entity only
+    let entity: WikidataEntity = serde_json::from_str(json_str)?;
+
+    // Resolving offline, the labels of all entities are written to the label table, so they
+    // are decoded first. Otherwise, the P31 (instance of) is checked before decoding anything.
+    let term_languages = &config.term_languages;
+    let mut labels = None;
+    let mut label_rows = Vec::new();
+    if config.resolver == "offline" {
+        let decoded = language_values::<Term>(entity.labels, term_languages);
+        if let Ok(decoded) = &decoded {
+            let chain_labels: Vec<Option<&str>> = config
+                .languages
+                .iter()
+                .map(|lang| {
+                    first_in_chain(&config.fallback, term_languages, decoded, lang)
+                        .map(|(label, _)| label.value.as_ref())
+                })
+                .collect();
+            label_rows = LabelTable::rows(entity.id, &chain_labels, &config.languages);
+        }
+        labels = Some(decoded);
+    }
+    // A skipped entity still has its label rows, which are moved into the extraction
+    let skipped = |reason, label_rows| Extraction {
+        skipped: Some(reason),
+        label_rows,
+        ..Default::default()
+    };
+
+    let instances = entity.instance_of();
+    let entity_types: Vec<&String> = config
+        .entity_types
+        .iter()
+        .filter(|entity_type| {
+            entity_mappings
+                .get(entity_type.as_str())
+                .is_some_and(|classes| instances.iter().any(|instance| classes.contains(*instance)))
+        })
+        .collect();
+    if entity_types.is_empty() {
+        return Ok(skipped(SkipReason::NoMatchingType, label_rows));
+    }
+
+    // Decode the terms of the languages and their fallback languages in one go. Missing
+    // descriptions and aliases are left out of the KV entry.
+    let (Ok(labels), Ok(descriptions), Ok(aliases)) = (
+        labels.unwrap_or_else(|| language_values::<Term>(entity.labels, term_languages)),
+        language_values::<Term>(entity.descriptions, term_languages),
+        language_values::<Vec<Alias>>(entity.aliases, term_languages),
+    ) else {
+        return Ok(skipped(SkipReason::InvalidTerms, label_rows));
+    };
+    let aliases: Vec<Option<Vec<Alias>>> = aliases
+        .into_iter()
+        .map(|aliases| aliases.filter(|aliases| !aliases.is_empty()))
+        .collect();
+    let terms: Vec<Terms> = config
+        .languages
+        .iter()
+        .map(|lang| {
+            let label = first_in_chain(&config.fallback, term_languages, &labels, lang);
+            let description = first_in_chain(&config.fallback, term_languages, &descriptions, lang);
+            let alias_list = first_in_chain(&config.fallback, term_languages, &aliases, lang);
+            let fallbacks = [
+                ("label", label.map(|(_, source)| source)),
+                ("descr", description.map(|(_, source)| source)),
+                ("alias", alias_list.map(|(_, source)| source)),
+            ]
+            .into_iter()
+            .filter_map(|(field, source)| Some((field, source?)))
+            .filter(|(_, source)| source != lang)
+            .collect();
+            let label = label.map(|(label, _)| label.value.as_ref());
+            Terms {
+                lang,
+                label,
+                description: description.map_or("", |(description, _)| description.value.as_ref()),
+                aliases: alias_list
+                    .into_iter()
+                    .flat_map(|(aliases, _)| aliases)
+                    .map(|alias| alias.value.as_ref())
+                    .filter(|alias| Some(*alias) != label)
+                    .collect(),
+                fallbacks,
+            }
+        })
+        .collect();
+    // Entities without a label in any of the languages cannot be looked up by name
+    if terms.iter().all(|terms| terms.label.is_none()) {
+        return Ok(skipped(SkipReason::NoLabel, label_rows));
+    }
+
+    let claims = entity.claims().unwrap_or_default();
+    // The properties of the types may overlap, so the statements that could not be decoded
+    // are counted once, for the type with the most of them
+    let mut invalid_statements = 0;
+    let records = entity_types
+        .into_iter()
+        .map(|entity_type| {
+            let (used_names, kv_entry, feature, invalid) = prepare_data_export(
+                resolver,
+                entity_type,
+                entity.id,
+                &claims,
+                config,
+                lookups,
+                &terms,
+            );
+            invalid_statements = invalid_statements.max(invalid);
+            EntityRecord {
+                entity_id: entity.id.to_string(),
+                used_names,
+                kv_entry,
+                feature,
+            }
+        })
+        .collect();
+    Ok(Extraction {
+        records,
+        label_rows,
+        invalid_statements,
+        ..Default::default()
+    })
 }
 
-/// Prepare the data for export
+/// Prepare the data for export: the names, the KV entry, and the GeoJSON feature if the
+/// entity can be located
+#[allow(clippy::too_many_arguments)]
 fn prepare_data_export(
-    resolver: &EntityResolver,
-    entity_type: &String,
+    resolver: Option<&EntityResolver>,
+    entity_type: &str,
     entity_id: &str,
-    claims: &Map<String, Value>,
+    claims: &HashMap<&str, &RawValue>,
     config: &Config,
-    default_properties: &HashMap<&str, Vec<&str>>,
-    label: &str,
-    aliases: &Vec<&str>,
-    description: &str,
-) -> (HashSet<String>, Value) {
-    let properties = &resolver.resolve_entity_ids(extract_properties(
-        entity_type,
-        &Value::Object(claims.clone()),
-        config.process_images,
-        default_properties,
-    ));
-
-    let mut used_names = HashSet::with_capacity(6);
-    used_names.insert(label.to_string());
-
-    for key in ["P1813" /* Short name */, "P1449" /* Nickname */] {
-        if let Some(alt_name_val) = properties.get(key) {
-            if let Some(alt_name) = alt_name_val.as_str() {
-                used_names.insert(alt_name.to_string());
-            }
-        }
-    }
+    lookups: &Lookups,
+    terms: &[Terms],
+) -> (UsedNames, Value, Option<Value>, u64) {
+    let property_definitions = config
+        .definitions
+        .get(entity_type)
+        .map_or(&[][..], |definition| definition.properties.as_slice());
+    let Properties {
+        values: properties,
+        ranks,
+        invalid_statements,
+    } = extract_properties(claims, config, &lookups.chains, property_definitions);
+    // Locate the entity before the IDs of its locations are resolved to labels
+    let coordinates = config.geojson.as_ref().and_then(|_| {
+        lookups
+            .coordinates
+            .locate(claims, &properties, property_definitions)
+    });
+    let properties = &match resolver {
+        Some(resolver) => resolver.resolve_entity_ids(properties),
+        None => properties,
+    };
 
-    for alias in aliases {
-        if !used_names.contains(*alias) {
-            used_names.insert(alias.to_string());
-        }
+    // Short names and nicknames, in all languages
+    let mut alt_names: Vec<&str> = Vec::new();
+    for id in ["P1813" /* Short name */, "P1449" /* Nickname */] {
+        let key = property_definitions
+            .iter()
+            .find(|property| property.id == id)
+            .map_or(id, |property| property.key.as_str());
+        // A single name, or an array of names
+        let values = match properties.get(key) {
+            Some(Value::Array(values)) => values.iter().collect(),
+            Some(value) => vec![value],
+            None => vec![],
+        };
+        // Names with qualifiers are objects with the name as value
+        alt_names.extend(
+            values
+                .into_iter()
+                .filter_map(|value| value.get("value").unwrap_or(value).as_str()),
+        );
     }
 
+    // Keep the names in a fixed order, so runs are reproducible
+    let used_names = terms
+        .iter()
+        .filter_map(|terms| {
+            let label = terms.label?;
+            let mut names: Vec<String> = Vec::with_capacity(6);
+            for name in std::iter::once(label)
+                .chain(alt_names.iter().copied())
+                .chain(terms.aliases.iter().copied())
+            {
+                if !names.iter().any(|used| used == name) {
+                    names.push(name.to_string());
+                }
+            }
+            let csv_name = OutputPaths::csv_name(entity_type, terms.lang, &config.languages);
+            Some((csv_name, names))
+        })
+        .collect();
+
     let mut entity_data = serde_json::Map::new();
-    entity_data.insert("label".to_string(), json!(label));
+    if let [terms] = terms {
+        entity_data.insert("label".to_string(), json!(terms.label.unwrap_or_default()));
 
-    // Conditionally add description if not empty
-    if !description.is_empty() {
-        entity_data.insert("descr".to_string(), json!(description));
-    }
+        // Conditionally add description if not empty
+        if !terms.description.is_empty() {
+            entity_data.insert("descr".to_string(), json!(terms.description));
+        }
+
+        // Conditionally add aliases if not empty
+        if !terms.aliases.is_empty() {
+            entity_data.insert("alias".to_string(), json!(terms.aliases));
+        }
 
-    // Conditionally add aliases if not empty
-    if !aliases.is_empty() {
-        entity_data.insert("alias".to_string(), json!(aliases));
+        // Record the languages of the terms that were taken from a fallback language
+        if !terms.fallbacks.is_empty() {
+            let fallbacks: Map<String, Value> = terms
+                .fallbacks
+                .iter()
+                .map(|(field, source)| (field.to_string(), json!(source)))
+                .collect();
+            entity_data.insert("lang".to_string(), Value::Object(fallbacks));
+        }
+    } else {
+        // With multiple languages, the terms are maps by language, of the languages that have them
+        let labels: Map<String, Value> = terms
+            .iter()
+            .filter_map(|terms| Some((terms.lang.to_string(), json!(terms.label?))))
+            .collect();
+        entity_data.insert("label".to_string(), Value::Object(labels));
+        let descriptions: Map<String, Value> = terms
+            .iter()
+            .filter(|terms| !terms.description.is_empty())
+            .map(|terms| (terms.lang.to_string(), json!(terms.description)))
+            .collect();
+        if !descriptions.is_empty() {
+            entity_data.insert("descr".to_string(), Value::Object(descriptions));
+        }
+        let aliases: Map<String, Value> = terms
+            .iter()
+            .filter(|terms| !terms.aliases.is_empty())
+            .map(|terms| (terms.lang.to_string(), json!(terms.aliases)))
+            .collect();
+        if !aliases.is_empty() {
+            entity_data.insert("alias".to_string(), Value::Object(aliases));
+        }
+        let mut fallbacks = Map::new();
+        for terms in terms {
+            for (field, source) in &terms.fallbacks {
+                let field = fallbacks
+                    .entry(field.to_string())
+                    .or_insert_with(|| Value::Object(Map::new()));
+                field[terms.lang] = json!(source);
+            }
+        }
+        if !fallbacks.is_empty() {
+            entity_data.insert("lang".to_string(), Value::Object(fallbacks));
+        }
     }
 
     // Always add properties
-    if properties.len() > 0 {
+    if !properties.is_empty() {
         entity_data.insert("props".to_string(), json!(properties));
     }
 
+    // Add the ranks of the properties for which they were requested
+    if !ranks.is_empty() {
+        entity_data.insert("ranks".to_string(), json!(ranks));
+    }
+
+    let feature = coordinates.map(|coordinates| {
+        feature(
+            entity_id,
+            entity_type,
+            coordinates,
+            &entity_data,
+            properties,
+            &config.geojson_properties,
+        )
+    });
     let kv_entry = json!({
         entity_id: entity_data
     });
-    (used_names, kv_entry)
+    (used_names, kv_entry, feature, invalid_statements)
+}
+
+/// Write the records of an entity and its rows in the label table, or count why it was skipped
+fn write_extraction(
+    batched_writer: &mut BatchedWriter,
+    extraction: Extraction,
+    skipped: &mut SkipCounts,
+) -> Result<(), ProcessingError> {
+    for record in extraction.records {
+        write_entity_data(batched_writer, record)?;
+    }
+    if let Some(reason) = extraction.skipped {
+        skipped.add(reason);
+    }
+    skipped.invalid_statements += extraction.invalid_statements;
+    batched_writer.add_label_rows(extraction.label_rows)
 }
 
 fn write_entity_data(
     batched_writer: &mut BatchedWriter,
-    entity_type: &str,
-    entity_id: &str,
-    used_names: HashSet<String>,
-    kv_entry: Value,
+    record: EntityRecord,
 ) -> Result<(), ProcessingError> {
-    for used_name in used_names {
-        batched_writer.add_csv_entry(
-            entity_type.to_string(),
-            (used_name.to_string(), entity_id.to_string()),
-        )?;
+    for (csv_name, used_names) in record.used_names {
+        for used_name in used_names {
+            batched_writer
+                .add_csv_entry(csv_name.clone(), (used_name, record.entity_id.clone()))?;
+        }
+    }
+    batched_writer.add_kv_entry(record.kv_entry)?;
+    if let Some(feature) = record.feature {
+        batched_writer.add_feature(feature)?;
     }
-    batched_writer.add_kv_entry(kv_entry)?;
     Ok(())
 }
 
+/// Extract the values of each property from its statements of the best rank, normalized
+/// according to their datatype
 fn extract_properties(
-    entity_type: &str,
-    claims: &Value,
-    process_images: bool,
-    default_properties: &HashMap<&str, Vec<&str>>,
-) -> Map<String, Value> {
+    claims: &HashMap<&str, &RawValue>,
+    config: &Config,
+    chains: &PropertyChains,
+    all_properties: &[PropertyDefinition],
+) -> Properties {
     let mut properties = serde_json::Map::new();
+    let mut ranks = serde_json::Map::new();
+    let mut invalid_statements = 0;
 
-    match default_properties.get(entity_type) {
-        Some(all_properties) => {
-            for prop in all_properties {
-                if let Some(value) = claims
-                    .get(prop)
-                    .and_then(|p| p.as_array())
-                    .and_then(|array| array.get(0))
-                {
-                    match *prop {
-                        "P569" | "P570" | "P571" => {
-                            // Simplify date fields (e.g., P569 = Date of Birth, P570 = Date of Death)
-                            if let Some(date) = value
-                                .get("mainsnak")
-                                .and_then(|ms| ms.get("datavalue"))
-                                .and_then(|dv| dv.get("value"))
-                                .and_then(|v| v.get("time"))
-                            {
-                                // Strip precision and metadata, and format date
-                                let simple_date =
-                                    date.as_str().unwrap_or("").trim_start_matches('+');
-                                properties.insert(
-                                    prop.to_string(),
-                                    Value::String(simple_date.to_string()),
-                                );
-                            }
-                        }
-                        "P17" | "P112" | "P27" | "P106" | "P39" | "P1454" | "P749" | "P101"
-                        | "P452" | "P276" | "P31" | "P585" | "P1552" | "P1889" | "P461"
-                        | "P460" | "P1382" => {
-                            // Handle string or entity-id properties (e.g., country, occupation, position)
-                            if let Some(id_value) = value
-                                .get("mainsnak")
-                                .and_then(|ms| ms.get("datavalue"))
-                                .and_then(|dv| dv.get("value"))
-                                .and_then(|v| v.get("id"))
-                            {
-                                properties.insert(prop.to_string(), id_value.clone());
-                            }
-                        }
-                        "P18" | "P154" => {
-                            // Extract base64-decoded image (P18 = Image property)
-                            if let Some(commons_url) = value
-                                .get("mainsnak")
-                                .and_then(|ms| ms.get("datavalue"))
-                                .and_then(|dv| dv.get("value"))
-                                .and_then(|v| v.as_str())
-                            {
-                                if process_images {
-                                    if let Some(url) = create_image_thumbnail_url(commons_url, None)
-                                    {
-                                        if let Ok(base64_image) = fetch_base64_image(url) {
-                                            properties.insert(
-                                                "image".to_string(),
-                                                Value::String(base64_image),
-                                            );
-                                        }
-                                    }
-                                } else {
-                                    properties.insert(
-                                        "image".to_string(),
-                                        Value::String(commons_url.to_string()),
-                                    );
-                                }
-                            }
-                        }
-                        "P159" => {
-                            // Extract location address or entity
-                            if let Some(location) = value
-                                .get("mainsnak")
-                                .and_then(|ms| ms.get("datavalue"))
-                                .and_then(|dv| dv.get("value"))
-                            {
-                                properties.insert(prop.to_string(), location.clone());
-                            }
-                        }
-                        "P1813" | "P1449" => {
-                            // Extract short name or alias
-                            if let Some(short_name) = value
-                                .get("mainsnak")
-                                .and_then(|ms| ms.get("datavalue"))
-                                .and_then(|dv| dv.get("value"))
-                                .and_then(|v| v.get("text"))
-                            {
-                                properties.insert(
-                                    prop.to_string(),
-                                    Value::String(short_name.as_str().unwrap_or("").to_string()),
-                                );
-                            }
-                        }
-                        "P856" | "P3220" => {
-                            // Extract URLs (P856 = Official website, P3220 = Google Maps ID)
-                            if let Some(url) = value
-                                .get("mainsnak")
-                                .and_then(|ms| ms.get("datavalue"))
-                                .and_then(|dv| dv.get("value"))
-                                .and_then(|v| v.as_str())
-                            {
-                                properties.insert(prop.to_string(), Value::String(url.to_string()));
-                            }
-                        }
-                        _ => {
-                            properties.insert(prop.to_string(), value.clone());
-                        }
-                    }
-                }
+    for property in all_properties {
+        // Only the statements of the requested properties are decoded
+        let Some(raw_statements) = claims.get(property.id.as_str()) else {
+            continue;
+        };
+        let (statements, invalid) = parse_statements(raw_statements);
+        invalid_statements += invalid;
+        let mut statements = best_rank_statements(&statements);
+        // Of a quantity, take the latest of the values with a point in time, e.g. the latest
+        // population. Other values are taken in the order of the statements.
+        let quantity = statements
+            .iter()
+            .any(|statement| statement.mainsnak.datatype() == Some("quantity"));
+        if quantity && !property.all {
+            statements.sort_by_key(|statement| Reverse(point_in_time(statement)));
+        }
+        let Some(rank) = statements.first().map(|statement| statement.rank) else {
+            continue;
+        };
+        let mut values = statements.iter().filter_map(|statement| {
+            let value = snak_value(
+                &statement.mainsnak,
+                config.process_images,
+                &config.unit_conversions,
+            )?;
+            if property.qualifiers.is_empty() {
+                Some(value)
+            } else {
+                Some(value_with_qualifiers(
+                    statement,
+                    value,
+                    &property.qualifiers,
+                    config.process_images,
+                    &config.unit_conversions,
+                ))
+            }
+        });
+        let value = if property.chain {
+            values
+                .next()
+                .map(|value| chains.chain(&property.id, value, property.max))
+        } else if property.all {
+            let values: Vec<Value> = values.take(property.max.unwrap_or(usize::MAX)).collect();
+            (!values.is_empty()).then_some(Value::Array(values))
+        } else {
+            values.next()
+        };
+        if let Some(value) = value {
+            properties.insert(property.key.clone(), value);
+            if property.rank {
+                ranks.insert(property.key.clone(), json!(rank));
             }
         }
-        None => {}
     }
-    properties
+    Properties {
+        values: properties,
+        ranks,
+        invalid_statements,
+    }
 }
 
 fn main() -> Result<(), ProcessingError> {
     let (input_file, config) = get_configuration()?;
 
-    process_wikidata(input_file, config)
+    match input_file {
+        Some(input_file) => process_wikidata(input_file, config),
+        None => resolve_deferred(&config),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A statement with an item as value
+    fn item_statement(property: &str, id: &str, rank: &str, qualifiers: Value) -> Value {
+        json!({
+            "mainsnak": {
+                "snaktype": "value",
+                "property": property,
+                "datavalue": {"value": {"entity-type": "item", "id": id}, "type": "wikibase-entityid"},
+                "datatype": "wikibase-item",
+            },
+            "type": "statement",
+            "rank": rank,
+            "qualifiers": qualifiers,
+        })
+    }
+
+    /// A qualifier with a point in time at day precision, in the Gregorian calendar
+    fn time_qualifier(property: &str, time: &str) -> Value {
+        json!([{
+            "snaktype": "value",
+            "property": property,
+            "datavalue": {
+                "value": {
+                    "time": time,
+                    "precision": 11,
+                    "calendarmodel": "http://www.wikidata.org/entity/Q1985727",
+                },
+                "type": "time",
+            },
+            "datatype": "time",
+        }])
+    }
+
+    /// A statement with a dimensionless quantity as value, at a point in time
+    fn quantity_statement(property: &str, amount: &str, point_in_time: &str) -> Value {
+        json!({
+            "mainsnak": {
+                "snaktype": "value",
+                "property": property,
+                "datavalue": {"value": {"amount": amount, "unit": "1"}, "type": "quantity"},
+                "datatype": "quantity",
+            },
+            "type": "statement",
+            "rank": "normal",
+            "qualifiers": {"P585": time_qualifier("P585", point_in_time)},
+        })
+    }
+
+    /// A property that is exported with the default options
+    fn property(id: &str, key: &str) -> PropertyDefinition {
+        PropertyDefinition {
+            id: id.to_string(),
+            key: key.to_string(),
+            all: false,
+            max: None,
+            rank: false,
+            qualifiers: Vec::new(),
+            chain: false,
+        }
+    }
+
+    /// Terms in each language, as in the dump
+    fn terms(terms: &[(&str, &str)]) -> Value {
+        terms
+            .iter()
+            .map(|(lang, value)| (lang.to_string(), json!({"language": lang, "value": value})))
+            .collect::<Map<String, Value>>()
+            .into()
+    }
+
+    /// A line of the dump with a person (Q5), with the given labels, descriptions and aliases
+    fn person_line(fields: Value) -> String {
+        let mut entity = json!({
+            "type": "item",
+            "id": "Q7747",
+            "claims": {"P31": [item_statement("P31", "Q5", "normal", json!({}))]},
+        });
+        entity
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        format!("{},", entity)
+    }
+
+    /// The configuration of a test in the output directory, with languages and fallback languages
+    fn language_config(output_dir: &Path, languages: &[&str], fallback: &[&str]) -> Config {
+        let mut config = Config::for_test(output_dir);
+        config.languages = languages.iter().map(|lang| lang.to_string()).collect();
+        config.fallback = fallback.iter().map(|lang| lang.to_string()).collect();
+        config.term_languages = config::term_languages(&config.languages, &config.fallback);
+        config
+    }
+
+    /// Extract the entity on a line of the dump
+    fn extract(line: &str, config: &Config) -> Extraction {
+        let entity_mappings = direct_entity_types(config);
+        extract_entities(line, None, config, &entity_mappings, &Lookups::default()).unwrap()
+    }
+
+    /// The KV entry of the single record of an extraction, without its properties
+    fn kv_entry(extraction: &Extraction) -> Value {
+        let [record] = &extraction.records[..] else {
+            panic!("expected a single record");
+        };
+        let mut entry = record.kv_entry[&record.entity_id].clone();
+        entry.as_object_mut().unwrap().remove("props");
+        entry
+    }
+
+    /// Extract the properties from the claims of an entity
+    fn properties(claims: Value, properties: &[PropertyDefinition]) -> Properties {
+        let claims = claims.to_string();
+        let claims: HashMap<&str, &RawValue> = serde_json::from_str(&claims).unwrap();
+        let config = Config::for_test(&std::env::temp_dir());
+        extract_properties(&claims, &config, &PropertyChains::default(), properties)
+    }
+
+    #[test]
+    fn extracts_the_values_of_the_best_rank_and_records_it() {
+        let claims = json!({"P39": [
+            item_statement("P39", "Q1", "deprecated", json!({})),
+            item_statement("P39", "Q2", "normal", json!({})),
+            item_statement("P39", "Q3", "preferred", json!({})),
+            item_statement("P39", "Q4", "preferred", json!({})),
+        ]});
+        let first = PropertyDefinition {
+            rank: true,
+            ..property("P39", "position")
+        };
+        let extracted = properties(claims.clone(), &[first]);
+        assert_eq!(extracted.values["position"], json!("Q3"));
+        assert_eq!(extracted.ranks["position"], json!("preferred"));
+
+        let all = PropertyDefinition {
+            all: true,
+            ..property("P39", "position")
+        };
+        let extracted = properties(claims.clone(), std::slice::from_ref(&all));
+        assert_eq!(extracted.values["position"], json!(["Q3", "Q4"]));
+        // The rank is only recorded when it is requested
+        assert!(extracted.ranks.is_empty());
+        let capped = PropertyDefinition {
+            max: Some(1),
+            rank: true,
+            ..all
+        };
+        let extracted = properties(claims, &[capped]);
+        assert_eq!(extracted.values["position"], json!(["Q3"]));
+        assert_eq!(extracted.ranks["position"], json!("preferred"));
+    }
+
+    #[test]
+    fn never_extracts_deprecated_values() {
+        let claims = json!({"P39": [
+            item_statement("P39", "Q1", "deprecated", json!({})),
+            item_statement("P39", "Q2", "normal", json!({})),
+        ]});
+        let position = PropertyDefinition {
+            all: true,
+            rank: true,
+            ..property("P39", "position")
+        };
+        let extracted = properties(claims, std::slice::from_ref(&position));
+        assert_eq!(extracted.values["position"], json!(["Q2"]));
+        assert_eq!(extracted.ranks["position"], json!("normal"));
+
+        let claims = json!({"P39": [item_statement("P39", "Q1", "deprecated", json!({}))]});
+        let extracted = properties(claims, &[position]);
+        assert!(extracted.values.is_empty());
+        assert!(extracted.ranks.is_empty());
+    }
+
+    #[test]
+    fn extracts_multiple_values_as_an_array_up_to_the_maximum() {
+        let claims = json!({"P39": [
+            item_statement("P39", "Q11", "normal", json!({})),
+            item_statement("P39", "Q12", "normal", json!({})),
+            item_statement("P39", "Q13", "normal", json!({})),
+        ]});
+        let positions = PropertyDefinition {
+            all: true,
+            max: Some(2),
+            ..property("P39", "positions")
+        };
+        let extracted = properties(claims, &[positions]);
+        assert_eq!(extracted.values["positions"], json!(["Q11", "Q12"]));
+
+        // Each value of the array is resolved
+        let source = label_source::MemorySource::new(&[("Q11", &["mayor"]), ("Q12", &["senator"])]);
+        let resolver = EntityResolver::new(None, Box::new(source), &["en".to_string()]);
+        assert_eq!(
+            Value::Object(resolver.resolve_entity_ids(extracted.values)),
+            json!({"positions": ["mayor", "senator"]})
+        );
+    }
+
+    #[test]
+    fn extracts_values_with_their_qualifiers() {
+        let of = item_statement("P642", "Q55", "normal", json!({}))["mainsnak"].clone();
+        let claims = json!({"P39": [item_statement("P39", "Q83307", "normal", json!({
+            "P580": time_qualifier("P580", "+2010-10-14T00:00:00Z"),
+            "P582": time_qualifier("P582", "+2017-10-26T00:00:00Z"),
+            "P642": [of],
+        }))]});
+        let position = PropertyDefinition {
+            qualifiers: ["P580", "P582", "P585", "P642"].map(String::from).to_vec(),
+            ..property("P39", "position")
+        };
+        let extracted = properties(claims, &[position]);
+        // Qualifiers without a value are left out
+        assert_eq!(
+            extracted.values["position"],
+            json!({
+                "value": "Q83307",
+                "start": {"date": "2010-10-14", "precision": "day"},
+                "end": {"date": "2017-10-26", "precision": "day"},
+                "of": "Q55",
+            })
+        );
+    }
+
+    #[test]
+    fn extracts_the_latest_quantity() {
+        let claims = json!({"P1082": [
+            quantity_statement("P1082", "+905234", "+2020-01-01T00:00:00Z"),
+            quantity_statement("P1082", "+931298", "+2023-01-01T00:00:00Z"),
+            quantity_statement("P1082", "+872680", "+2017-01-01T00:00:00Z"),
+        ]});
+        let extracted = properties(claims, &[property("P1082", "population")]);
+        assert_eq!(extracted.values["population"], json!({"amount": 931298}));
+
+        // Other values are taken in the order of the statements, whatever their point in time
+        let claims = json!({"P39": [
+            item_statement("P39", "Q1", "normal", json!({
+                "P585": time_qualifier("P585", "+2000-01-01T00:00:00Z"),
+            })),
+            item_statement("P39", "Q2", "normal", json!({
+                "P585": time_qualifier("P585", "+2020-01-01T00:00:00Z"),
+            })),
+        ]});
+        let extracted = properties(claims, &[property("P39", "position")]);
+        assert_eq!(extracted.values["position"], json!("Q1"));
+    }
+
+    #[test]
+    fn extracts_terms_in_multiple_languages_to_a_file_per_language() {
+        let dir = std::env::temp_dir().join(format!("languages_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = language_config(&dir, &["en", "nl"], &[]);
+        config.entity_types = vec!["person".to_string()];
+        config.output_format = "JSONLines".to_string();
+        let line = person_line(json!({
+            "labels": terms(&[("en", "Peter the Great"), ("nl", "Peter de Grote"), ("de", "Peter der Große")]),
+            "descriptions": terms(&[("en", "tsar of Russia")]),
+            "aliases": {"nl": [{"language": "nl", "value": "Pjotr I"}]},
+        }));
+
+        let extraction = extract(&line, &config);
+        assert_eq!(
+            kv_entry(&extraction),
+            json!({
+                "label": {"en": "Peter the Great", "nl": "Peter de Grote"},
+                "descr": {"en": "tsar of Russia"},
+                "alias": {"nl": ["Pjotr I"]},
+            })
+        );
+        let paths = OutputPaths::new(&config, None);
+        let mut writer = BatchedWriter::create(&paths, config.output_format.clone(), 10).unwrap();
+        write_extraction(&mut writer, extraction, &mut SkipCounts::default()).unwrap();
+        writer.finalize().unwrap();
+        drop(writer);
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        let (english, dutch) = (read("person.en.csv"), read("person.nl.csv"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(english, "Peter the Great,Q7747\n");
+        assert_eq!(dutch, "Peter de Grote,Q7747\nPjotr I,Q7747\n");
+    }
+
+    #[test]
+    fn records_the_languages_of_terms_from_a_fallback_language() {
+        let dir = std::env::temp_dir();
+        let line = person_line(json!({
+            "labels": terms(&[("mul", "Johann Sebastian Bach"), ("en", "J. S. Bach")]),
+            "descriptions": terms(&[("en", "German composer"), ("de", "deutscher Komponist")]),
+            "aliases": {"nl": [{"language": "nl", "value": "Bach"}]},
+        }));
+
+        // The label is taken from mul before English, the aliases are in the language itself
+        let extraction = extract(&line, &language_config(&dir, &["nl"], &["mul", "en"]));
+        assert_eq!(
+            kv_entry(&extraction),
+            json!({
+                "label": "Johann Sebastian Bach",
+                "descr": "German composer",
+                "alias": ["Bach"],
+                "lang": {"label": "mul", "descr": "en"},
+            })
+        );
+
+        // With multiple languages, the languages are recorded by language
+        let extraction = extract(&line, &language_config(&dir, &["en", "de"], &["mul"]));
+        assert_eq!(
+            kv_entry(&extraction),
+            json!({
+                "label": {"en": "J. S. Bach", "de": "Johann Sebastian Bach"},
+                "descr": {"en": "German composer", "de": "deutscher Komponist"},
+                "lang": {"label": {"de": "mul"}},
+            })
+        );
+    }
+
+    #[test]
+    fn extracts_entities_with_a_label_and_a_type_and_counts_the_others() {
+        let dir = std::env::temp_dir().join(format!("inclusion_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = language_config(&dir, &["nl"], &[]);
+        let label = terms(&[("nl", "Rembrandt")]);
+        let kept = [
+            // Without descriptions and aliases
+            json!({"labels": label}),
+            // With empty language maps, which the dumps write as arrays
+            json!({"labels": label, "descriptions": [], "aliases": []}),
+            // With an empty list of aliases
+            json!({"labels": label, "aliases": {"nl": []}}),
+        ];
+        for fields in kept {
+            let extraction = extract(&person_line(fields), &config);
+            assert!(extraction.skipped.is_none());
+            assert_eq!(kv_entry(&extraction), json!({"label": "Rembrandt"}));
+        }
+
+        let skipped = [
+            // Not an instance of one of the types, e.g. a disambiguation page
+            json!({"labels": label, "claims": {"P31": [item_statement("P31", "Q4167410", "normal", json!({}))]}}),
+            // Only a label in another language, without a fallback
+            json!({"labels": terms(&[("en", "Rembrandt")])}),
+            json!({"labels": []}),
+            // Terms that are not language maps
+            json!({"labels": label, "descriptions": "Dutch painter"}),
+            json!({"labels": ["Rembrandt"]}),
+        ];
+        let paths = OutputPaths::new(&config, None);
+        let mut writer = BatchedWriter::create(&paths, config.output_format.clone(), 10).unwrap();
+        let mut counts = SkipCounts::default();
+        for fields in skipped {
+            let extraction = extract(&person_line(fields), &config);
+            assert!(extraction.records.is_empty());
+            write_extraction(&mut writer, extraction, &mut counts).unwrap();
+        }
+        drop(writer);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(counts.no_matching_type, 1);
+        assert_eq!(counts.no_label, 2);
+        assert_eq!(counts.invalid_terms, 2);
+        assert_eq!(counts.total(), 5);
+
+        // With a fallback, a label in another language is enough
+        let config = language_config(&dir, &["nl"], &["en"]);
+        let extraction = extract(
+            &person_line(json!({"labels": terms(&[("en", "Rembrandt")])})),
+            &config,
+        );
+        assert_eq!(
+            kv_entry(&extraction),
+            json!({"label": "Rembrandt", "lang": {"label": "en"}})
+        );
+    }
 }
@@ -0,0 +1,175 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::processing_error::ProcessingError;
+
+/// Number of characters of a rejected line that are kept
+const REJECT_PREFIX_LENGTH: usize = 200;
+
+/// Counts the lines that could not be parsed, and enforces the maximum number of errors
+pub struct ParseReport {
+    malformed: AtomicU64,
+    max_errors: Option<u64>,
+}
+
+impl ParseReport {
+    pub fn new(malformed: u64, max_errors: Option<u64>) -> Self {
+        Self {
+            malformed: AtomicU64::new(malformed),
+            max_errors,
+        }
+    }
+
+    /// Register a malformed line, failing once there are more than allowed
+    pub fn register(&self) -> Result<(), ProcessingError> {
+        let malformed = self.malformed.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.max_errors {
+            Some(max_errors) if malformed > max_errors => {
+                Err(ProcessingError::TooManyErrors(max_errors))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn malformed(&self) -> u64 {
+        self.malformed.load(Ordering::Relaxed)
+    }
+}
+
+/// Why an entity of the dump is not extracted
+#[derive(Debug, Clone, Copy)]
+pub enum SkipReason {
+    /// None of its P31 (instance of) values is a class of the requested types
+    NoMatchingType,
+    /// It has no label in any of the languages, not even in a fallback language
+    NoLabel,
+    /// Its labels, descriptions or aliases are not language maps
+    InvalidTerms,
+}
+
+/// Number of entities that were skipped, for each reason, and of the statements of the
+/// extracted entities that were skipped because they could not be decoded
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SkipCounts {
+    pub no_matching_type: u64,
+    pub no_label: u64,
+    pub invalid_terms: u64,
+    #[serde(default)]
+    pub invalid_statements: u64,
+}
+
+impl SkipCounts {
+    pub fn add(&mut self, reason: SkipReason) {
+        match reason {
+            SkipReason::NoMatchingType => self.no_matching_type += 1,
+            SkipReason::NoLabel => self.no_label += 1,
+            SkipReason::InvalidTerms => self.invalid_terms += 1,
+        }
+    }
+
+    /// Add the counts of another byte range
+    pub fn merge(&mut self, other: &SkipCounts) {
+        self.no_matching_type += other.no_matching_type;
+        self.no_label += other.no_label;
+        self.invalid_terms += other.invalid_terms;
+        self.invalid_statements += other.invalid_statements;
+    }
+
+    /// Number of skipped entities
+    pub fn total(&self) -> u64 {
+        self.no_matching_type + self.no_label + self.invalid_terms
+    }
+}
+
+/// A line that could not be parsed, as written to the rejects file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RejectedLine {
+    /// Line number, starting at 1
+    pub line: u64,
+    /// Byte offset of the start of the line (in the decompressed input)
+    pub offset: u64,
+    pub error: String,
+    /// Start of the line, to help finding out what went wrong
+    pub prefix: String,
+}
+
+impl RejectedLine {
+    pub fn new(line: u64, offset: u64, error: String, content: &[u8]) -> Self {
+        let prefix =
+            String::from_utf8_lossy(&content[..content.len().min(REJECT_PREFIX_LENGTH * 4)])
+                .chars()
+                .take(REJECT_PREFIX_LENGTH)
+                .collect();
+        Self {
+            line,
+            offset,
+            error,
+            prefix,
+        }
+    }
+}
+
+/// Workers only know the line numbers within their own byte range. Once all ranges are done,
+/// convert them to line numbers in the whole file, given the start offset of each range and
+/// the number of lines before it.
+pub fn renumber_rejects(path: &Path, line_bases: &[(u64, u64)]) -> Result<(), ProcessingError> {
+    let tmp_path = path.with_extension("jsonl.tmp");
+    {
+        let reader = BufReader::new(File::open(path)?);
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for line in reader.lines() {
+            let mut reject: RejectedLine = serde_json::from_str(&line?)?;
+            let range = line_bases.partition_point(|(start, _)| *start <= reject.offset);
+            if range > 0 {
+                reject.line += line_bases[range - 1].1;
+            }
+            writeln!(writer, "{}", serde_json::to_string(&reject)?)?;
+        }
+        writer.flush()?;
+    }
+    std::fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renumbers_rejects_of_all_parts() {
+        let path =
+            std::env::temp_dir().join(format!("renumber_rejects_{}.jsonl", std::process::id()));
+        // The first part starts at byte 0, the second at byte 1000, after 10 lines
+        let rejects = [
+            RejectedLine::new(3, 200, "first".to_string(), b"{"),
+            RejectedLine::new(1, 1000, "second".to_string(), b"{"),
+            RejectedLine::new(2, 1100, "third".to_string(), b"{"),
+        ];
+        let lines: Vec<String> = rejects
+            .iter()
+            .map(|reject| serde_json::to_string(reject).unwrap() + "\n")
+            .collect();
+        std::fs::write(&path, lines.concat()).unwrap();
+
+        renumber_rejects(&path, &[(0, 0), (1000, 10)]).unwrap();
+        let renumbered: Vec<(u64, String)> = BufReader::new(File::open(&path).unwrap())
+            .lines()
+            .map(|line| {
+                let reject: RejectedLine = serde_json::from_str(&line.unwrap()).unwrap();
+                (reject.line, reject.error)
+            })
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            renumbered,
+            vec![
+                (3, "first".to_string()),
+                (11, "second".to_string()),
+                (12, "third".to_string())
+            ]
+        );
+    }
+}
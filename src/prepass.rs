@@ -0,0 +1,372 @@
+use memchr::memmem;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use crate::chains::{chain_edges, PropertyChains};
+use crate::class_hierarchy::{qid_number, subclass_edges, ClassHierarchy};
+use crate::config::Config;
+use crate::dump_reader::{
+    detect_compression, map_dump, open_dump, read_line, split_ranges, Compression, Lines,
+    STREAM_BATCH_SIZE,
+};
+use crate::geojson::{entity_coordinates, has_location_properties, CoordinateIndex};
+use crate::processing_error::ProcessingError;
+use crate::progress::{ProgressTracker, PROGRESS_INTERVAL};
+use crate::wikidata_entity::WikidataEntity;
+
+/// Scan all lines of the dump in a first pass, collecting what `extract` returns for each line.
+/// Lines are processed in parallel, and the results are returned in the order of the dump.
+pub fn scan_dump<T, F>(input_path: &Path, extract: F) -> Result<Vec<T>, ProcessingError>
+where
+    T: Send,
+    F: Fn(&[u8]) -> Vec<T> + Sync,
+{
+    if detect_compression(input_path)? == Compression::None {
+        let data = map_dump(input_path)?;
+        let ranges = split_ranges(&data, rayon::current_num_threads());
+        let progress = ProgressTracker::new(ranges.len(), data.len() as u64);
+        let results: Vec<Vec<T>> = ranges
+            .into_par_iter()
+            .enumerate()
+            .map(|(index, range)| {
+                let start = range.start;
+                let mut results = Vec::new();
+                for (count, (offset, line)) in Lines::new(&data, range).enumerate() {
+                    if count % PROGRESS_INTERVAL == 0 {
+                        progress.set(index, (offset - start) as u64);
+                        progress.report()?;
+                    }
+                    results.extend(extract(line));
+                }
+                Ok(results)
+            })
+            .collect::<Result<_, ProcessingError>>()?;
+        progress.finish();
+        Ok(results.into_iter().flatten().collect())
+    } else {
+        let mut dump = open_dump(input_path)?;
+        let progress = ProgressTracker::new(1, dump.file_size);
+        let mut results = Vec::new();
+        loop {
+            let mut batch = Vec::with_capacity(STREAM_BATCH_SIZE);
+            while batch.len() < STREAM_BATCH_SIZE {
+                let mut line = Vec::new();
+                if read_line(&mut dump.reader, &mut line)? == 0 {
+                    break;
+                }
+                line.truncate(line.trim_ascii_end().len());
+                batch.push(line);
+            }
+            if batch.is_empty() {
+                break;
+            }
+            let batch_results: Vec<Vec<T>> = batch.par_iter().map(|line| extract(line)).collect();
+            results.extend(batch_results.into_iter().flatten());
+            progress.set(0, dump.bytes_read.load(Ordering::Relaxed));
+            progress.report()?;
+        }
+        progress.finish();
+        Ok(results)
+    }
+}
+
+/// Lookups of other entities in the dump, read in a first pass when they are needed
+#[derive(Default, Serialize, Deserialize)]
+pub struct Lookups {
+    /// Values of chained properties, such as located in (P131)
+    pub chains: PropertyChains,
+    /// Coordinates of all entities, to locate GeoJSON features by the item of their location
+    pub coordinates: CoordinateIndex,
+}
+
+/// Settings that determine the lookups, stored in the cache to detect when it is outdated
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct LookupSettings {
+    input: String,
+    input_size: u64,
+    /// Properties that are exported as chains
+    chained_properties: Vec<String>,
+    /// Whether the coordinates of all entities are read
+    coordinates: bool,
+}
+
+impl LookupSettings {
+    fn is_empty(&self) -> bool {
+        self.chained_properties.is_empty() && !self.coordinates
+    }
+}
+
+/// Cached lookups, together with the settings they were read with
+#[derive(Serialize, Deserialize)]
+struct LookupCache {
+    settings: LookupSettings,
+    lookups: Lookups,
+}
+
+/// What the first pass reads of an entity
+enum Record {
+    /// Subclass-of edge (subclass, class)
+    Subclass(u64, u64),
+    /// Value of a chained property (property index, entity, value)
+    Chain(usize, u64, u64),
+    /// Coordinates of an entity
+    Coordinates(u64, (f64, f64)),
+}
+
+/// What the first pass reads, and the patterns to skip the entities without it quickly
+struct Scan<'a> {
+    subclasses: bool,
+    /// Settings of the lookups to read, or `None` when they are cached
+    lookups: Option<&'a LookupSettings>,
+    finders: Vec<memmem::Finder<'static>>,
+}
+
+impl Scan<'_> {
+    /// The records of the entity on a line of the dump
+    fn records(&self, line: &[u8]) -> Vec<Record> {
+        // Most entities have none of the properties, so skip them without parsing them
+        if self
+            .finders
+            .iter()
+            .all(|finder| finder.find(line).is_none())
+        {
+            return Vec::new();
+        }
+        let Ok(line) = std::str::from_utf8(line) else {
+            return Vec::new();
+        };
+        let Ok(entity) = serde_json::from_str::<WikidataEntity>(line.trim_end_matches(',')) else {
+            return Vec::new();
+        };
+        let Some(entity_id) = qid_number(entity.id) else {
+            return Vec::new();
+        };
+        let mut records = Vec::new();
+        if self.subclasses {
+            records.extend(
+                subclass_edges(&entity)
+                    .into_iter()
+                    .map(|(subclass, class)| Record::Subclass(subclass, class)),
+            );
+        }
+        let (Some(settings), Some(claims)) = (self.lookups, entity.claims()) else {
+            return records;
+        };
+        records.extend(
+            chain_edges(entity_id, &claims, &settings.chained_properties)
+                .into_iter()
+                .map(|(index, entity, parent)| Record::Chain(index, entity, parent)),
+        );
+        if settings.coordinates {
+            records.extend(
+                entity_coordinates(&claims)
+                    .map(|coordinates| Record::Coordinates(entity_id, coordinates)),
+            );
+        }
+        records
+    }
+}
+
+fn lookup_cache_path(config: &Config) -> PathBuf {
+    PathBuf::from(
+        config
+            .lookup_cache
+            .clone()
+            .unwrap_or_else(|| format!("{}/lookups.msgpack", config.output_dir)),
+    )
+}
+
+impl Lookups {
+    /// Read the lookups that the requested entity types need, and with `subclasses` the class
+    /// hierarchy, in a single first pass over the dump. The lookups are cached, so resumed runs
+    /// and later runs over the same dump with the same settings only read the class hierarchy,
+    /// if needed.
+    pub fn read(
+        input_path: &Path,
+        config: &Config,
+        subclasses: bool,
+    ) -> Result<(Self, Option<ClassHierarchy>), ProcessingError> {
+        let definitions: Vec<_> = config
+            .entity_types
+            .iter()
+            .filter_map(|entity_type| config.definitions.get(entity_type))
+            .collect();
+        let mut chained_properties: Vec<String> = definitions
+            .iter()
+            .flat_map(|definition| &definition.properties)
+            .filter(|property| property.chain)
+            .map(|property| property.id.clone())
+            .collect();
+        chained_properties.sort();
+        chained_properties.dedup();
+        // The own coordinates of an entity are in its claims, only those of its location are
+        // looked up
+        let coordinates = config.geojson.is_some()
+            && definitions
+                .iter()
+                .any(|definition| has_location_properties(&definition.properties));
+        let settings = LookupSettings {
+            input: input_path.to_string_lossy().to_string(),
+            input_size: fs::metadata(input_path)?.len(),
+            chained_properties,
+            coordinates,
+        };
+
+        let cache_path = lookup_cache_path(config);
+        let cached = if settings.is_empty() {
+            Some(Self::default())
+        } else {
+            File::open(&cache_path)
+                .ok()
+                .and_then(|file| rmp_serde::from_read::<_, LookupCache>(BufReader::new(file)).ok())
+                .filter(|cache| cache.settings == settings)
+                .map(|cache| {
+                    println!("Using the lookups in {}", cache_path.display());
+                    cache.lookups
+                })
+        };
+        let cached = match cached {
+            Some(lookups) if !subclasses => return Ok((lookups, None)),
+            cached => cached,
+        };
+
+        let mut patterns: Vec<String> = Vec::new();
+        let mut reading: Vec<String> = Vec::new();
+        if subclasses {
+            patterns.push("\"P279\"".to_string());
+            reading.push("the class hierarchy".to_string());
+        }
+        if cached.is_none() {
+            patterns.extend(
+                settings
+                    .chained_properties
+                    .iter()
+                    .map(|property| format!("\"{}\"", property)),
+            );
+            if !settings.chained_properties.is_empty() {
+                reading.push(format!(
+                    "the chains of {}",
+                    settings.chained_properties.join(", ")
+                ));
+            }
+            if settings.coordinates {
+                patterns.push("\"P625\"".to_string());
+                reading.push("the coordinates".to_string());
+            }
+        }
+        let last = reading.pop().unwrap_or_default();
+        let reading = match reading.is_empty() {
+            true => last,
+            false => format!("{} and {}", reading.join(", "), last),
+        };
+        println!("Reading {} from {}", reading, input_path.display());
+        let scan = Scan {
+            subclasses,
+            lookups: cached.is_none().then_some(&settings),
+            finders: patterns
+                .iter()
+                .map(|pattern| memmem::Finder::new(pattern.as_bytes()).into_owned())
+                .collect(),
+        };
+        let records = scan_dump(input_path, |line| scan.records(line))?;
+
+        let mut subclass_of = Vec::new();
+        let mut chains = Vec::new();
+        let mut coordinates = Vec::new();
+        for record in records {
+            match record {
+                Record::Subclass(subclass, class) => subclass_of.push((subclass, class)),
+                Record::Chain(index, entity, parent) => chains.push((index, entity, parent)),
+                Record::Coordinates(entity, position) => coordinates.push((entity, position)),
+            }
+        }
+        let hierarchy = subclasses.then(|| ClassHierarchy::from_edges(subclass_of));
+        let lookups = match cached {
+            Some(lookups) => lookups,
+            None => {
+                let cache = LookupCache {
+                    lookups: Self {
+                        chains: PropertyChains::from_edges(&settings.chained_properties, chains),
+                        coordinates: coordinates.into_iter().collect(),
+                    },
+                    settings,
+                };
+                // Write to a temporary file first, so an interrupted run never leaves a
+                // partial cache
+                let tmp_path = cache_path.with_extension("msgpack.tmp");
+                let mut writer = BufWriter::new(File::create(&tmp_path)?);
+                rmp_serde::encode::write(&mut writer, &cache)?;
+                writer.flush()?;
+                fs::rename(&tmp_path, &cache_path)?;
+                cache.lookups
+            }
+        };
+        Ok((lookups, hierarchy))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statement(property: &str, value: &str) -> String {
+        let datavalue = match value.strip_prefix('Q') {
+            Some(id) => format!(
+                r#"{{"value":{{"entity-type":"item","numeric-id":{},"id":"{}"}},"type":"wikibase-entityid"}}"#,
+                id, value
+            ),
+            None => format!(
+                r#"{{"value":{{"latitude":{},"longitude":5.0}},"type":"globecoordinate"}}"#,
+                value
+            ),
+        };
+        format!(
+            r#""{}":[{{"mainsnak":{{"snaktype":"value","property":"{}","datavalue":{}}},"rank":"normal"}}]"#,
+            property, property, datavalue
+        )
+    }
+
+    #[test]
+    fn reads_all_records_of_an_entity_at_once() {
+        let line = format!(
+            r#"{{"id":"Q3","type":"item","claims":{{{},{},{}}}}},"#,
+            statement("P279", "Q1"),
+            statement("P131", "Q2"),
+            statement("P625", "52.0")
+        );
+        let settings = LookupSettings {
+            input: String::new(),
+            input_size: 0,
+            chained_properties: vec!["P131".to_string()],
+            coordinates: true,
+        };
+        let scan = Scan {
+            subclasses: true,
+            lookups: Some(&settings),
+            finders: vec![memmem::Finder::new("\"P279\"")],
+        };
+        let records = scan.records(line.as_bytes());
+        assert!(matches!(
+            records[..],
+            [
+                Record::Subclass(3, 1),
+                Record::Chain(0, 3, 2),
+                Record::Coordinates(3, (52.0, 5.0))
+            ]
+        ));
+
+        // Without the lookups, e.g. when they are cached, only the subclasses are read
+        let scan = Scan {
+            lookups: None,
+            ..scan
+        };
+        assert!(matches!(
+            scan.records(line.as_bytes())[..],
+            [Record::Subclass(3, 1)]
+        ));
+    }
+}
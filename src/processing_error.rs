@@ -5,6 +5,18 @@ pub enum ProcessingError {
     JsonError(serde_json::Error),
     CsvError(csv::Error),
     MessagePackError(rmp_serde::encode::Error),
+    MessagePackDecodeError(rmp_serde::decode::Error),
+    CheckpointError(String),
+    /// More lines could not be parsed than the maximum number of errors
+    TooManyErrors(u64),
+    /// The dump is truncated, or does not have the structure of a JSON array of entities
+    IncompleteDump(String),
+    /// The checksum of the dump does not match the published one
+    ChecksumError(String),
+    /// Invalid settings or definitions
+    ConfigError(String),
+    /// A request to the Wikidata API failed, also after retrying
+    NetworkError(String),
     // Other(String),
 }
 
@@ -15,6 +27,17 @@ impl std::fmt::Display for ProcessingError {
             ProcessingError::JsonError(e) => write!(f, "JSON Error: {}", e),
             ProcessingError::CsvError(e) => write!(f, "CSV Error: {}", e),
             ProcessingError::MessagePackError(e) => write!(f, "MessagePack Error: {}", e),
+            ProcessingError::MessagePackDecodeError(e) => write!(f, "MessagePack Error: {}", e),
+            ProcessingError::CheckpointError(e) => write!(f, "Checkpoint Error: {}", e),
+            ProcessingError::TooManyErrors(max) => write!(
+                f,
+                "Too many errors: more than {} lines could not be parsed",
+                max
+            ),
+            ProcessingError::IncompleteDump(e) => write!(f, "Incomplete dump: {}", e),
+            ProcessingError::ChecksumError(e) => write!(f, "Checksum Error: {}", e),
+            ProcessingError::ConfigError(e) => write!(f, "Config Error: {}", e),
+            ProcessingError::NetworkError(e) => write!(f, "Network Error: {}", e),
             // ProcessingError::Other(e) => write!(f, "Processing Error: {}", e),
         }
     }
@@ -45,3 +68,9 @@ impl From<rmp_serde::encode::Error> for ProcessingError {
         ProcessingError::MessagePackError(error)
     }
 }
+
+impl From<rmp_serde::decode::Error> for ProcessingError {
+    fn from(error: rmp_serde::decode::Error) -> Self {
+        ProcessingError::MessagePackDecodeError(error)
+    }
+}
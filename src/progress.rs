@@ -0,0 +1,88 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Number of lines a worker processes between progress updates
+pub const PROGRESS_INTERVAL: usize = 1000;
+
+/// Progress over the input, combined from the offsets reached by each worker
+pub struct ProgressTracker {
+    /// Bytes processed per worker (or per byte range)
+    offsets: Vec<AtomicU64>,
+    /// Total number of bytes to process
+    total: u64,
+    start_time: Instant,
+    last_reported_promille: AtomicU64,
+}
+
+impl ProgressTracker {
+    pub fn new(workers: usize, total: u64) -> Self {
+        Self {
+            offsets: (0..workers).map(|_| AtomicU64::new(0)).collect(),
+            total,
+            start_time: Instant::now(),
+            last_reported_promille: AtomicU64::new(0),
+        }
+    }
+
+    /// Set the number of bytes processed by a worker
+    pub fn set(&self, worker: usize, processed: u64) {
+        self.offsets[worker].store(processed, Ordering::Relaxed);
+    }
+
+    /// Total number of bytes processed by all workers
+    pub fn processed(&self) -> u64 {
+        self.offsets
+            .iter()
+            .map(|offset| offset.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Report progress with 0.1% granularity
+    pub fn report(&self) -> std::io::Result<()> {
+        if self.total == 0 {
+            return Ok(());
+        }
+        let current_promille = ((self.processed() as f64 / self.total as f64) * 1000.0) as u64;
+        let last_promille = self.last_reported_promille.load(Ordering::Relaxed);
+        if current_promille.saturating_sub(last_promille) >= 1 {
+            // Use compare_exchange to ensure only one thread updates the progress
+            if self
+                .last_reported_promille
+                .compare_exchange(
+                    last_promille,
+                    current_promille,
+                    Ordering::SeqCst,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                let elapsed = self.start_time.elapsed();
+                let eta = if current_promille > 0 {
+                    let total_estimated_time =
+                        elapsed.as_secs_f64() / (current_promille as f64 / 1000.0);
+                    Duration::from_secs_f64((total_estimated_time - elapsed.as_secs_f64()).max(0.0))
+                } else {
+                    Duration::from_secs(0)
+                };
+
+                print!(
+                    "\rProcessing: {:.1}% | Elapsed: {:.0}s | ETA: {:.0}s         ",
+                    current_promille as f64 / 10.0,
+                    elapsed.as_secs(),
+                    eta.as_secs()
+                );
+                std::io::stdout().flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Clear the progress line
+    pub fn finish(&self) {
+        println!(
+            "\rProcessing: 100% | Completed in {:.0}s                 ",
+            self.start_time.elapsed().as_secs()
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use serde_json::{json, Map, Value};
+
+use crate::dates::{chronological_key, normalize_time};
+use crate::units::UnitConversions;
+use crate::utils::{create_image_thumbnail_url, fetch_base64_image};
+use crate::wikibase::{DataValue, QuantityValue, Snak, Statement};
+
+/// Normalized value of a snak, based on its datatype and value type:
+/// - items, properties and lexemes: the ID, of which only item IDs are resolved to a label
+///   later on
+/// - points in time: the date at its precision, e.g. `{"date": "1879-03-14", "precision": "day"}`
+/// - quantities: `{amount, lower, upper, unit}`, with the bounds and unit only when given, and
+///   converted to the canonical unit when there is a conversion for the unit
+/// - coordinates: `{lat, lon}`
+/// - monolingual text: the text
+/// - images (commonsMedia): the file name, or the base64 encoded thumbnail when processing images
+/// - strings, URLs and external IDs: the string
+///
+/// Returns `None` when the snak has no value (somevalue or novalue), or the value is not supported.
+pub fn snak_value(
+    snak: &Snak,
+    process_images: bool,
+    unit_conversions: &UnitConversions,
+) -> Option<Value> {
+    match snak.datavalue()? {
+        DataValue::EntityId(entity) => Some(Value::String(entity.id.clone())),
+        DataValue::Time(time) => normalize_time(time),
+        DataValue::Quantity(quantity) => quantity_value(quantity, unit_conversions),
+        DataValue::GlobeCoordinate(coordinate) => Some(json!({
+            "lat": coordinate.latitude,
+            "lon": coordinate.longitude,
+        })),
+        DataValue::MonolingualText(text) => Some(Value::String(text.text.clone())),
+        DataValue::String(file_name) if snak.datatype() == Some("commonsMedia") => {
+            if process_images {
+                let url = create_image_thumbnail_url(file_name, None)?;
+                fetch_base64_image(url).ok().map(Value::String)
+            } else {
+                Some(Value::String(file_name.clone()))
+            }
+        }
+        DataValue::String(string) => Some(Value::String(string.clone())),
+        DataValue::Unsupported => None,
+    }
+}
+
+/// Parse a decimal amount like `+1234.5`, optionally multiplying it by a conversion factor
+fn amount(amount: &str, factor: Option<f64>) -> Option<Value> {
+    let amount = amount.trim_start_matches('+');
+    match factor {
+        Some(factor) => serde_json::Number::from_f64(amount.parse::<f64>().ok()? * factor),
+        // Keep the exact amount when it is not converted
+        None => amount.parse::<serde_json::Number>().ok(),
+    }
+    .map(Value::Number)
+}
+
+/// Normalized quantity, where the unit is the QID of the item (resolved to a label later on)
+fn quantity_value(quantity: &QuantityValue, unit_conversions: &UnitConversions) -> Option<Value> {
+    // The unit is `1` for dimensionless quantities, otherwise the URI of the unit item
+    let unit = quantity
+        .unit
+        .rsplit_once('/')
+        .map(|(_, unit)| unit.to_string());
+    let conversion = unit.as_deref().and_then(|unit| unit_conversions.get(unit));
+    let factor = conversion.map(|conversion| conversion.factor);
+
+    let mut object = Map::new();
+    object.insert("amount".to_string(), amount(&quantity.amount, factor)?);
+    if let Some(lower) = quantity.lower_bound.as_deref() {
+        object.extend(amount(lower, factor).map(|lower| ("lower".to_string(), lower)));
+    }
+    if let Some(upper) = quantity.upper_bound.as_deref() {
+        object.extend(amount(upper, factor).map(|upper| ("upper".to_string(), upper)));
+    }
+    match conversion {
+        Some(conversion) => {
+            object.insert("unit".to_string(), Value::String(conversion.unit.clone()));
+        }
+        None => {
+            object.extend(unit.map(|unit| ("unit".to_string(), Value::String(unit))));
+        }
+    }
+    Some(Value::Object(object))
+}
+
+/// Point in time (P585) of a statement, e.g. the year of a population count or revenue
+pub fn point_in_time(statement: &Statement) -> Option<(i64, u32, u32)> {
+    statement
+        .qualifiers
+        .get("P585")?
+        .iter()
+        .find_map(|snak| match snak.datavalue()? {
+            DataValue::Time(time) => chronological_key(time),
+            _ => None,
+        })
+}
+
+/// Key of a qualifier in an exported value: a name for common qualifiers, otherwise the PID
+pub fn qualifier_key(property: &str) -> &str {
+    match property {
+        "P580" => "start",       // Start time
+        "P582" => "end",         // End time
+        "P585" => "time",        // Point in time
+        "P625" => "coordinates", // Coordinate location
+        "P642" => "of",          // Of
+        _ => property,
+    }
+}
+
+/// Value of a statement together with the requested qualifiers, e.g. `{value, start, end, of}`.
+/// Of each qualifier, the first snak with a value is exported.
+pub fn value_with_qualifiers(
+    statement: &Statement,
+    value: Value,
+    qualifiers: &[String],
+    process_images: bool,
+    unit_conversions: &UnitConversions,
+) -> Value {
+    let mut object = Map::new();
+    object.insert("value".to_string(), value);
+    for qualifier in qualifiers {
+        let qualifier_value = statement
+            .qualifiers
+            .get(qualifier)
+            .into_iter()
+            .flatten()
+            .find_map(|snak| snak_value(snak, process_images, unit_conversions));
+        if let Some(qualifier_value) = qualifier_value {
+            object.insert(qualifier_key(qualifier).to_string(), qualifier_value);
+        }
+    }
+    Value::Object(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quantity(amount: &str, bounds: Option<(&str, &str)>, unit: &str) -> QuantityValue {
+        QuantityValue {
+            amount: amount.to_string(),
+            lower_bound: bounds.map(|(lower, _)| lower.to_string()),
+            upper_bound: bounds.map(|(_, upper)| upper.to_string()),
+            unit: unit.to_string(),
+        }
+    }
+
+    fn kilometres_to_metres() -> UnitConversions {
+        UnitConversions::parse(r#"Q828224 = { unit = "Q11573", factor = 1000.0 }"#, "test").unwrap()
+    }
+
+    #[test]
+    fn keeps_the_exact_amount_and_bounds_without_conversion() {
+        let value = quantity_value(
+            &quantity(
+                "+1234.50",
+                Some(("+1200", "+1300")),
+                "http://www.wikidata.org/entity/Q4917",
+            ),
+            &kilometres_to_metres(),
+        );
+        assert_eq!(
+            value,
+            Some(json!({"amount": 1234.5, "lower": 1200, "upper": 1300, "unit": "Q4917"}))
+        );
+    }
+
+    #[test]
+    fn converts_the_amount_and_bounds_to_the_canonical_unit() {
+        let value = quantity_value(
+            &quantity(
+                "+1.5",
+                Some(("+1.25", "+1.75")),
+                "http://www.wikidata.org/entity/Q828224",
+            ),
+            &kilometres_to_metres(),
+        );
+        assert_eq!(
+            value,
+            Some(json!({"amount": 1500.0, "lower": 1250.0, "upper": 1750.0, "unit": "Q11573"}))
+        );
+    }
+
+    #[test]
+    fn exports_dimensionless_quantities_without_unit() {
+        // The unit `1` is not an item
+        let value = quantity_value(&quantity("-3", None, "1"), &kilometres_to_metres());
+        assert_eq!(value, Some(json!({"amount": -3})));
+    }
+
+    #[test]
+    fn skips_invalid_amounts() {
+        let conversions = kilometres_to_metres();
+        assert_eq!(
+            quantity_value(&quantity("many", None, "1"), &conversions),
+            None
+        );
+        // An invalid bound is left out
+        let value = quantity_value(&quantity("+7", Some(("?", "+8")), "1"), &conversions);
+        assert_eq!(value, Some(json!({"amount": 7, "upper": 8})));
+    }
+}
@@ -0,0 +1,67 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::processing_error::ProcessingError;
+
+/// Conversion of a unit to a canonical unit
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UnitConversion {
+    /// QID of the canonical unit
+    pub unit: String,
+    /// Amount in the canonical unit of one of the converted unit
+    pub factor: f64,
+}
+
+/// Conversions of quantities to canonical units, by the QID of the unit to convert
+#[derive(Debug, Clone, Default)]
+pub struct UnitConversions {
+    conversions: HashMap<String, UnitConversion>,
+}
+
+impl UnitConversions {
+    /// Load the conversions from a TOML file, or no conversions at all
+    pub fn load(path: Option<&Path>) -> Result<Self, ProcessingError> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        Self::parse(&fs::read_to_string(path)?, &path.display().to_string())
+    }
+
+    /// Parse the conversions from TOML, where `source` names the TOML in errors
+    pub fn parse(conversions: &str, source: &str) -> Result<Self, ProcessingError> {
+        let conversions = toml::from_str(conversions).map_err(|e| {
+            ProcessingError::ConfigError(format!("invalid unit conversions in {}: {}", source, e))
+        })?;
+        Ok(Self { conversions })
+    }
+
+    pub fn get(&self, unit: &str) -> Option<&UnitConversion> {
+        self.conversions.get(unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_included_conversions() {
+        let conversions =
+            UnitConversions::load(Some(Path::new("data/unit_conversions.toml"))).unwrap();
+        let mile = conversions.get("Q253276").unwrap();
+        assert_eq!((mile.unit.as_str(), mile.factor), ("Q11573", 1609.344));
+        assert!(conversions.get("Q11573").is_none());
+        assert!(UnitConversions::load(None)
+            .unwrap()
+            .get("Q253276")
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_conversions_without_a_factor() {
+        assert!(UnitConversions::parse(r#"Q828224 = { unit = "Q11573" }"#, "test").is_err());
+    }
+}
@@ -50,7 +50,7 @@ pub fn generate_browser_headers() -> HeaderMap {
     let mut rng = rand::thread_rng();
 
     // List of plausible user agents
-    let user_agents = vec![
+    let user_agents = [
       "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/121.0.0.0 Safari/537.36",
       "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.3112.101 Safari/537.36",
       "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/119.0.0.0 Safari/537.36",
@@ -59,7 +59,7 @@ pub fn generate_browser_headers() -> HeaderMap {
   ];
 
     // List of accept language headers
-    let accept_languages = vec![
+    let accept_languages = [
         "en-US,en;q=0.9",
         "en-GB,en;q=0.9",
         "en-CA,en;q=0.9",
@@ -67,7 +67,7 @@ pub fn generate_browser_headers() -> HeaderMap {
     ];
 
     // List of potential referrers
-    let referrers = vec![
+    let referrers = [
         "https://www.google.com/",
         "https://www.bing.com/",
         "https://www.wikipedia.org/",
@@ -0,0 +1,422 @@
+// Typed model of Wikibase statements, as found in the `claims` of the JSON dump.
+// See https://doc.wikimedia.org/Wikibase/master/php/docs_topics_json.html
+// The model covers the whole format, but extraction does not use the statement IDs, the order
+// of the qualifiers, the properties of the snaks and the references, which are only decoded when
+// they are asked for.
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Rank of a statement, ordered from least to most relevant
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rank {
+    Deprecated,
+    #[default]
+    Normal,
+    Preferred,
+}
+
+/// A statement (claim) about an entity, including its qualifiers and references
+#[derive(Debug, Clone, Deserialize)]
+pub struct Statement<'a> {
+    #[allow(dead_code)]
+    #[serde(default)]
+    pub id: Option<String>,
+    pub mainsnak: Snak,
+    #[serde(default)]
+    pub rank: Rank,
+    #[serde(default)]
+    pub qualifiers: HashMap<String, Vec<Snak>>,
+    #[allow(dead_code)]
+    #[serde(rename = "qualifiers-order", default)]
+    pub qualifiers_order: Vec<String>,
+    /// The references, still encoded, see `Statement::references`
+    #[allow(dead_code)]
+    #[serde(borrow, default)]
+    references: Option<&'a RawValue>,
+}
+
+#[allow(dead_code)]
+impl Statement<'_> {
+    /// The references of the statement, skipping references that do not fit the model
+    pub fn references(&self) -> Vec<Reference> {
+        self.references
+            .and_then(|raw| serde_json::from_str::<Vec<&RawValue>>(raw.get()).ok())
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|reference| serde_json::from_str(reference.get()).ok())
+            .collect()
+    }
+}
+
+/// A reference, i.e. the source of a statement
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct Reference {
+    #[serde(default)]
+    pub hash: Option<String>,
+    #[serde(default)]
+    pub snaks: HashMap<String, Vec<Snak>>,
+    #[serde(rename = "snaks-order", default)]
+    pub snaks_order: Vec<String>,
+}
+
+/// A property-value pair. Besides a value, a property may have an unknown value
+/// (somevalue), or explicitly no value at all (novalue).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "snaktype", rename_all = "lowercase")]
+pub enum Snak {
+    Value {
+        #[allow(dead_code)]
+        property: String,
+        #[serde(default)]
+        datatype: Option<String>,
+        datavalue: DataValue,
+    },
+    SomeValue {
+        #[allow(dead_code)]
+        property: String,
+        #[serde(default)]
+        datatype: Option<String>,
+    },
+    NoValue {
+        #[allow(dead_code)]
+        property: String,
+        #[serde(default)]
+        datatype: Option<String>,
+    },
+}
+
+impl Snak {
+    #[allow(dead_code)]
+    pub fn property(&self) -> &str {
+        match self {
+            Snak::Value { property, .. }
+            | Snak::SomeValue { property, .. }
+            | Snak::NoValue { property, .. } => property,
+        }
+    }
+
+    /// Datatype of the property, e.g. `wikibase-item`, `external-id` or `commonsMedia`
+    pub fn datatype(&self) -> Option<&str> {
+        match self {
+            Snak::Value { datatype, .. }
+            | Snak::SomeValue { datatype, .. }
+            | Snak::NoValue { datatype, .. } => datatype.as_deref(),
+        }
+    }
+
+    /// The value, if the snak has one
+    pub fn datavalue(&self) -> Option<&DataValue> {
+        match self {
+            Snak::Value { datavalue, .. } => Some(datavalue),
+            _ => None,
+        }
+    }
+}
+
+/// The value of a snak. Datatypes like `external-id`, `url` and `commonsMedia`
+/// are all stored as a string value.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", content = "value", try_from = "RawDataValue")]
+pub enum DataValue {
+    #[serde(rename = "string")]
+    String(String),
+    #[serde(rename = "wikibase-entityid")]
+    EntityId(EntityIdValue),
+    #[serde(rename = "time")]
+    Time(TimeValue),
+    #[serde(rename = "quantity")]
+    Quantity(QuantityValue),
+    #[serde(rename = "monolingualtext")]
+    MonolingualText(MonolingualTextValue),
+    #[serde(rename = "globecoordinate")]
+    GlobeCoordinate(GlobeCoordinateValue),
+    /// Any value type that is not (yet) supported
+    Unsupported,
+}
+
+/// A data value before its type is known
+#[derive(Deserialize)]
+struct RawDataValue {
+    #[serde(rename = "type")]
+    value_type: String,
+    value: Value,
+}
+
+impl TryFrom<RawDataValue> for DataValue {
+    type Error = serde_json::Error;
+
+    fn try_from(raw: RawDataValue) -> Result<Self, Self::Error> {
+        let value = raw.value;
+        Ok(match raw.value_type.as_str() {
+            "string" => DataValue::String(serde_json::from_value(value)?),
+            "wikibase-entityid" => DataValue::EntityId(serde_json::from_value(value)?),
+            "time" => DataValue::Time(serde_json::from_value(value)?),
+            "quantity" => DataValue::Quantity(serde_json::from_value(value)?),
+            "monolingualtext" => DataValue::MonolingualText(serde_json::from_value(value)?),
+            "globecoordinate" => DataValue::GlobeCoordinate(serde_json::from_value(value)?),
+            _ => DataValue::Unsupported,
+        })
+    }
+}
+
+/// Reference to another entity, e.g. an item (Q42) or a property (P31)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EntityIdValue {
+    #[serde(
+        rename = "entity-type",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub entity_type: Option<String>,
+    #[serde(
+        rename = "numeric-id",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub numeric_id: Option<u64>,
+    pub id: String,
+}
+
+/// Point in time, e.g. `+1879-03-14T00:00:00Z` with a precision of 11 (day)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TimeValue {
+    pub time: String,
+    #[serde(default)]
+    pub timezone: i64,
+    #[serde(default)]
+    pub before: u64,
+    #[serde(default)]
+    pub after: u64,
+    pub precision: u8,
+    pub calendarmodel: String,
+}
+
+/// Amount with optional bounds and a unit, where the unit is `1` or an item URI
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QuantityValue {
+    pub amount: String,
+    #[serde(
+        rename = "upperBound",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub upper_bound: Option<String>,
+    #[serde(
+        rename = "lowerBound",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub lower_bound: Option<String>,
+    pub unit: String,
+}
+
+/// Text in a specific language
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MonolingualTextValue {
+    pub text: String,
+    pub language: String,
+}
+
+/// Geographic coordinates on a globe (usually Earth, Q2)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GlobeCoordinateValue {
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub altitude: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub precision: Option<f64>,
+    #[serde(default)]
+    pub globe: String,
+}
+
+/// Parse the statements of a property, skipping statements that do not fit the model.
+/// Returns the statements, and the number of statements that were skipped, which is 1 when
+/// the statements are not an array at all.
+pub fn parse_statements(raw: &RawValue) -> (Vec<Statement<'_>>, u64) {
+    let Ok(raw_statements) = serde_json::from_str::<Vec<&RawValue>>(raw.get()) else {
+        return (Vec::new(), 1);
+    };
+    let total = raw_statements.len();
+    let statements: Vec<Statement> = raw_statements
+        .into_iter()
+        .filter_map(|statement| serde_json::from_str(statement.get()).ok())
+        .collect();
+    let invalid = (total - statements.len()) as u64;
+    (statements, invalid)
+}
+
+/// The statements of the best rank: the preferred statements if there are any, otherwise
+/// the normal ones. Deprecated statements are never selected.
+pub fn best_rank_statements<'a>(statements: &'a [Statement<'a>]) -> Vec<&'a Statement<'a>> {
+    let best_rank = statements
+        .iter()
+        .map(|statement| statement.rank)
+        .filter(|rank| *rank != Rank::Deprecated)
+        .max();
+    statements
+        .iter()
+        .filter(|statement| Some(statement.rank) == best_rank)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Vec<Statement<'_>> {
+        let raw: &RawValue = serde_json::from_str(json).unwrap();
+        parse_statements(raw).0
+    }
+
+    #[test]
+    fn parses_entity_id_with_qualifiers_and_references() {
+        let statements = parse(
+            r#"[{"mainsnak":{"snaktype":"value","property":"P39","datavalue":{"value":{"entity-type":"item","numeric-id":11696,"id":"Q11696"},"type":"wikibase-entityid"},"datatype":"wikibase-item"},"type":"statement",
+            "qualifiers":{"P580":[{"snaktype":"value","property":"P580","datavalue":{"value":{"time":"+2009-01-20T00:00:00Z","timezone":0,"before":0,"after":0,"precision":11,"calendarmodel":"http://www.wikidata.org/entity/Q1985727"},"type":"time"},"datatype":"time"}]},
+            "qualifiers-order":["P580"],"id":"Q76$1","rank":"preferred",
+            "references":[{"hash":"abc","snaks":{"P854":[{"snaktype":"value","property":"P854","datavalue":{"value":"https://example.org","type":"string"},"datatype":"url"}]},"snaks-order":["P854"]}]}]"#,
+        );
+        assert_eq!(statements.len(), 1);
+        let statement = &statements[0];
+        assert_eq!(statement.id.as_deref(), Some("Q76$1"));
+        assert_eq!(statement.rank, Rank::Preferred);
+        assert_eq!(statement.mainsnak.property(), "P39");
+        assert_eq!(statement.mainsnak.datatype(), Some("wikibase-item"));
+        assert!(
+            matches!(statement.mainsnak.datavalue(), Some(DataValue::EntityId(id)) if id.id == "Q11696")
+        );
+        assert!(matches!(
+            statement.qualifiers["P580"][0].datavalue(),
+            Some(DataValue::Time(time)) if time.precision == 11
+        ));
+        assert_eq!(statement.qualifiers_order, ["P580"]);
+        let references = statement.references();
+        assert_eq!(references.len(), 1);
+        assert_eq!(references[0].hash.as_deref(), Some("abc"));
+        assert_eq!(references[0].snaks_order, ["P854"]);
+        assert_eq!(references[0].snaks["P854"][0].datatype(), Some("url"));
+    }
+
+    #[test]
+    fn skips_references_that_do_not_fit_the_model() {
+        let statements = parse(
+            r#"[{"mainsnak":{"snaktype":"novalue","property":"P39"},"rank":"normal",
+            "references":[{"snaks":{"P854":[{"snaktype":"other"}]}},{"snaks":{}}]},
+            {"mainsnak":{"snaktype":"novalue","property":"P39"},"rank":"normal"}]"#,
+        );
+        assert_eq!(statements[0].references().len(), 1);
+        assert!(statements[1].references().is_empty());
+    }
+
+    #[test]
+    fn parses_all_value_types() {
+        let statements = parse(
+            r#"[
+            {"mainsnak":{"snaktype":"value","property":"P1082","datavalue":{"value":{"amount":"+905234","unit":"1","upperBound":"+905300","lowerBound":"+905100"},"type":"quantity"},"datatype":"quantity"},"rank":"normal"},
+            {"mainsnak":{"snaktype":"value","property":"P1813","datavalue":{"value":{"text":"NASA","language":"en"},"type":"monolingualtext"},"datatype":"monolingualtext"},"rank":"normal"},
+            {"mainsnak":{"snaktype":"value","property":"P625","datavalue":{"value":{"latitude":52.37,"longitude":4.89,"altitude":null,"precision":0.01,"globe":"http://www.wikidata.org/entity/Q2"},"type":"globecoordinate"},"datatype":"globe-coordinate"},"rank":"normal"},
+            {"mainsnak":{"snaktype":"value","property":"P214","datavalue":{"value":"113230702","type":"string"},"datatype":"external-id"},"rank":"deprecated"},
+            {"mainsnak":{"snaktype":"value","property":"P1","datavalue":{"value":{"some":"thing"},"type":"new-type"},"datatype":"new-type"},"rank":"normal"}
+            ]"#,
+        );
+        assert_eq!(statements.len(), 5);
+        assert!(matches!(
+            statements[0].mainsnak.datavalue(),
+            Some(DataValue::Quantity(quantity)) if quantity.amount == "+905234" && quantity.unit == "1"
+        ));
+        assert!(matches!(
+            statements[1].mainsnak.datavalue(),
+            Some(DataValue::MonolingualText(text)) if text.text == "NASA"
+        ));
+        assert!(matches!(
+            statements[2].mainsnak.datavalue(),
+            Some(DataValue::GlobeCoordinate(coordinate)) if coordinate.longitude == 4.89
+        ));
+        assert_eq!(statements[3].rank, Rank::Deprecated);
+        assert_eq!(statements[3].mainsnak.datatype(), Some("external-id"));
+        assert!(matches!(
+            statements[3].mainsnak.datavalue(),
+            Some(DataValue::String(id)) if id == "113230702"
+        ));
+        assert!(matches!(
+            statements[4].mainsnak.datavalue(),
+            Some(DataValue::Unsupported)
+        ));
+    }
+
+    #[test]
+    fn parses_somevalue_and_novalue() {
+        let statements = parse(
+            r#"[
+            {"mainsnak":{"snaktype":"somevalue","property":"P570","datatype":"time"},"rank":"normal"},
+            {"mainsnak":{"snaktype":"novalue","property":"P40","datatype":"wikibase-item"},"rank":"normal"}
+            ]"#,
+        );
+        assert!(matches!(statements[0].mainsnak, Snak::SomeValue { .. }));
+        assert!(matches!(statements[1].mainsnak, Snak::NoValue { .. }));
+        assert!(statements[1].mainsnak.datavalue().is_none());
+    }
+
+    fn ids<'a>(statements: Vec<&'a Statement>) -> Vec<&'a str> {
+        statements
+            .iter()
+            .filter_map(|statement| match statement.mainsnak.datavalue() {
+                Some(DataValue::EntityId(entity)) => Some(entity.id.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn selects_best_rank_statements() {
+        let statements = parse(
+            r#"[
+            {"mainsnak":{"snaktype":"value","property":"P39","datavalue":{"value":{"id":"Q1"},"type":"wikibase-entityid"}},"rank":"normal"},
+            {"mainsnak":{"snaktype":"value","property":"P39","datavalue":{"value":{"id":"Q2"},"type":"wikibase-entityid"}},"rank":"preferred"},
+            {"mainsnak":{"snaktype":"value","property":"P39","datavalue":{"value":{"id":"Q3"},"type":"wikibase-entityid"}},"rank":"preferred"}
+            ]"#,
+        );
+        assert_eq!(ids(best_rank_statements(&statements)), ["Q2", "Q3"]);
+
+        let statements = parse(
+            r#"[
+            {"mainsnak":{"snaktype":"value","property":"P138","datavalue":{"value":{"id":"Q1"},"type":"wikibase-entityid"}},"rank":"deprecated"},
+            {"mainsnak":{"snaktype":"value","property":"P138","datavalue":{"value":{"id":"Q2"},"type":"wikibase-entityid"}},"rank":"normal"}
+            ]"#,
+        );
+        assert_eq!(ids(best_rank_statements(&statements)), ["Q2"]);
+
+        let statements = parse(
+            r#"[{"mainsnak":{"snaktype":"value","property":"P138","datavalue":{"value":{"id":"Q1"},"type":"wikibase-entityid"}},"rank":"deprecated"}]"#,
+        );
+        assert!(best_rank_statements(&statements).is_empty());
+    }
+
+    #[test]
+    fn skips_malformed_statements() {
+        let statements = parse(
+            r#"[{"mainsnak":{"snaktype":"value","property":"P31"},"rank":"normal"},
+            {"mainsnak":{"snaktype":"value","property":"P31","datavalue":{"value":{"id":"Q5"},"type":"wikibase-entityid"}}}]"#,
+        );
+        assert_eq!(statements.len(), 1);
+        assert_eq!(statements[0].rank, Rank::Normal);
+    }
+
+    #[test]
+    fn counts_malformed_statements() {
+        let raw: Box<RawValue> = serde_json::from_str(
+            r#"[{"mainsnak":{"snaktype":"value","property":"P31"}},{"mainsnak":42},
+            {"mainsnak":{"snaktype":"novalue","property":"P31"}}]"#,
+        )
+        .unwrap();
+        let (statements, invalid) = parse_statements(&raw);
+        assert_eq!((statements.len(), invalid), (1, 2));
+        let raw: Box<RawValue> = serde_json::from_str(r#"{"P31":[]}"#).unwrap();
+        assert_eq!(parse_statements(&raw).1, 1);
+    }
+}
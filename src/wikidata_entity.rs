@@ -0,0 +1,284 @@
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+
+use crate::wikibase::Rank;
+
+/// A Wikidata entity, borrowing its fields from the line in the dump.
+/// Only the top-level structure is parsed: the fields themselves are decoded
+/// on demand, so most entities are discarded without allocating anything.
+#[derive(Debug, Deserialize)]
+pub struct WikidataEntity<'a> {
+    #[serde(borrow)]
+    pub id: &'a str,
+    #[serde(borrow)]
+    pub claims: Option<&'a RawValue>,
+    #[serde(borrow)]
+    pub labels: Option<&'a RawValue>,
+    #[serde(borrow)]
+    pub descriptions: Option<&'a RawValue>,
+    #[serde(borrow)]
+    pub aliases: Option<&'a RawValue>,
+}
+
+/// Label or description in a single language
+#[derive(Debug, Deserialize)]
+pub struct Term<'a> {
+    #[serde(borrow)]
+    pub value: Cow<'a, str>,
+}
+
+/// Alias in a single language
+#[derive(Debug, Deserialize)]
+pub struct Alias<'a> {
+    #[serde(default, borrow)]
+    pub value: Cow<'a, str>,
+}
+
+/// Only the P31 (instance of) statements of the claims, each decoded on its own
+#[derive(Debug, Deserialize)]
+struct InstanceOfClaims<'a> {
+    #[serde(rename = "P31", default, borrow)]
+    p31: Vec<&'a RawValue>,
+}
+
+/// Only the P279 (subclass of) statements of the claims, each decoded on its own
+#[derive(Debug, Deserialize)]
+struct SubclassOfClaims<'a> {
+    #[serde(rename = "P279", default, borrow)]
+    p279: Vec<&'a RawValue>,
+}
+
+/// A statement with an item as value, of which only the rank and the QID are decoded
+#[derive(Debug, Deserialize)]
+struct ItemStatement<'a> {
+    #[serde(borrow)]
+    mainsnak: ItemSnak<'a>,
+    #[serde(default)]
+    rank: Rank,
+}
+
+#[derive(Debug, Deserialize)]
+struct ItemSnak<'a> {
+    #[serde(default, borrow)]
+    datavalue: Option<ItemDataValue<'a>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ItemDataValue<'a> {
+    #[serde(borrow)]
+    value: ItemValue<'a>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ItemValue<'a> {
+    #[serde(default, borrow)]
+    id: Option<&'a str>,
+}
+
+/// The QIDs of a list of item statements. Deprecated statements and statements that cannot
+/// be decoded are skipped, without affecting the others.
+fn item_ids(statements: Vec<&RawValue>) -> Vec<&str> {
+    statements
+        .into_iter()
+        .filter_map(|statement| serde_json::from_str::<ItemStatement>(statement.get()).ok())
+        .filter(|statement| statement.rank != Rank::Deprecated)
+        .filter_map(|statement| statement.mainsnak.datavalue)
+        .filter_map(|datavalue| datavalue.value.id)
+        .collect()
+}
+
+impl<'a> WikidataEntity<'a> {
+    /// Cheap pre-check: the QIDs of the P31 (instance of) statements, skipping all other claims
+    pub fn instance_of(&self) -> Vec<&'a str> {
+        self.claims
+            .and_then(|claims| serde_json::from_str::<InstanceOfClaims>(claims.get()).ok())
+            .map(|claims| item_ids(claims.p31))
+            .unwrap_or_default()
+    }
+
+    /// The QIDs of the P279 (subclass of) statements, skipping all other claims
+    pub fn subclass_of(&self) -> Vec<&'a str> {
+        self.claims
+            .and_then(|claims| serde_json::from_str::<SubclassOfClaims>(claims.get()).ok())
+            .map(|claims| item_ids(claims.p279))
+            .unwrap_or_default()
+    }
+
+    /// All claims, as raw statement arrays per property, to be decoded for matching entities only
+    pub fn claims(&self) -> Option<HashMap<&'a str, &'a RawValue>> {
+        self.claims
+            .and_then(|claims| serde_json::from_str(claims.get()).ok())
+    }
+}
+
+/// Decode the values of the requested languages from a language map (labels, descriptions or
+/// aliases), skipping all other languages. The values are in the order of the languages.
+/// A missing field has no values. Returns `Err` when the field is not a language map at all.
+pub fn language_values<'a, T: Deserialize<'a>>(
+    field: Option<&'a RawValue>,
+    languages: &[String],
+) -> Result<Vec<Option<T>>, serde_json::Error> {
+    let Some(field) = field else {
+        return Ok(languages.iter().map(|_| None).collect());
+    };
+    let mut deserializer = serde_json::Deserializer::from_str(field.get());
+    LanguageSeed {
+        languages,
+        marker: PhantomData,
+    }
+    .deserialize(&mut deserializer)
+}
+
+struct LanguageSeed<'l, T> {
+    languages: &'l [String],
+    marker: PhantomData<T>,
+}
+
+impl<'de, T: Deserialize<'de>> DeserializeSeed<'de> for LanguageSeed<'_, T> {
+    type Value = Vec<Option<T>>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for LanguageSeed<'_, T> {
+    type Value = Vec<Option<T>>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map of languages")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut results: Vec<Option<T>> = self.languages.iter().map(|_| None).collect();
+        while let Some(key) = map.next_key::<Cow<'de, str>>()? {
+            match self.languages.iter().position(|lang| *lang == key) {
+                Some(index) if results[index].is_none() => {
+                    results[index] = Some(map.next_value::<T>()?);
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    /// The dumps serialize an empty language map as an empty array
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        if seq.next_element::<IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_type(de::Unexpected::Seq, &self));
+        }
+        Ok(self.languages.iter().map(|_| None).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn languages() -> Vec<String> {
+        vec!["nl".to_string(), "en".to_string()]
+    }
+
+    fn raw(json: &str) -> Box<RawValue> {
+        RawValue::from_string(json.to_string()).unwrap()
+    }
+
+    #[test]
+    fn decodes_requested_languages_in_order() {
+        let field = raw(r#"{"en":{"value":"Amsterdam"},"de":{"value":"Amsterdam (de)"}}"#);
+        let labels = language_values::<Term>(Some(&field), &languages()).unwrap();
+        let labels: Vec<Option<&str>> = labels
+            .iter()
+            .map(|label| label.as_ref().map(|label| label.value.as_ref()))
+            .collect();
+        assert_eq!(labels, vec![None, Some("Amsterdam")]);
+    }
+
+    #[test]
+    fn accepts_missing_fields_and_empty_arrays() {
+        let empty = raw("[]");
+        for field in [None, Some(empty.as_ref())] {
+            let aliases = language_values::<Vec<Alias>>(field, &languages()).unwrap();
+            assert!(aliases.iter().all(Option::is_none));
+        }
+    }
+
+    #[test]
+    fn rejects_other_values() {
+        for json in [r#"["en"]"#, r#""en""#, "42"] {
+            let field = raw(json);
+            assert!(language_values::<Term>(Some(&field), &languages()).is_err());
+        }
+    }
+
+    #[test]
+    fn skips_deprecated_and_invalid_instance_of_statements() {
+        let line = r#"{"id":"Q1","claims":{"P31":[
+            {"mainsnak":{"snaktype":"value","datavalue":{"value":{"id":"Q5"}}},"rank":"normal"},
+            {"mainsnak":{"snaktype":"value","datavalue":{"value":{"id":"Q6"}}},"rank":"deprecated"},
+            {"mainsnak":42},
+            {"mainsnak":{"snaktype":"novalue"},"rank":"preferred"},
+            {"mainsnak":{"snaktype":"value","datavalue":{"value":{"id":"Q7"}}}}
+        ]}}"#;
+        let entity: WikidataEntity = serde_json::from_str(line).unwrap();
+        assert_eq!(entity.instance_of(), vec!["Q5", "Q7"]);
+    }
+
+    /// Compares the pre-check with decoding whole entities into `Value` trees, as before the
+    /// borrowed parsing, on the first entities of a dump:
+    /// `WIKIDATA_BENCH_DUMP=latest-all.json cargo test --release pre_check -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn benchmark_pre_check() {
+        use std::io::BufRead;
+        use std::time::Instant;
+
+        let Ok(path) = std::env::var("WIKIDATA_BENCH_DUMP") else {
+            return;
+        };
+        let file = std::fs::File::open(path).unwrap();
+        let lines: Vec<String> = std::io::BufReader::new(file)
+            .lines()
+            .map(Result::unwrap)
+            .filter(|line| line.starts_with('{'))
+            .take(200_000)
+            .collect();
+        let lines: Vec<&str> = lines
+            .iter()
+            .map(|line| line.trim_end_matches(','))
+            .collect();
+
+        let start = Instant::now();
+        let mut tree_instances = 0;
+        for line in &lines {
+            let entity: serde_json::Value = serde_json::from_str(line).unwrap();
+            tree_instances += entity["claims"]["P31"].as_array().map_or(0, Vec::len);
+        }
+        let tree = start.elapsed();
+
+        let start = Instant::now();
+        let mut instances = 0;
+        for line in &lines {
+            let entity: WikidataEntity = serde_json::from_str(line).unwrap();
+            instances += entity.instance_of().len();
+        }
+        let borrowed = start.elapsed();
+
+        println!(
+            "{} entities: Value tree {:?} ({} P31), pre-check {:?} ({} P31), {:.1}x faster",
+            lines.len(),
+            tree,
+            tree_instances,
+            borrowed,
+            instances,
+            tree.as_secs_f64() / borrowed.as_secs_f64()
+        );
+    }
+}